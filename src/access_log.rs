@@ -0,0 +1,98 @@
+//! # access_log
+//! This module contains an xferlog-like log of data transfers,
+//! every transfer appends one line to the configured file:
+//!
+//! `<unix timestamp> <client ip> <bytes> <filename> <direction> <status>`
+//!
+//! - spaces in filename are replaced by `_` so that the line can always be split by whitespace
+//! - direction is `o` (outgoing, sent to client), the only one until uploads are implemented
+//! - status is `c` (complete) or `i` (incomplete)
+
+use anyhow::Result;
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    net::IpAddr,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+#[derive(Debug, Clone, Copy)]
+pub enum Direction {
+    Outgoing,
+}
+
+/// One finished (or failed) transfer
+pub struct TransferRecord<'a> {
+    pub client_ip: IpAddr,
+    pub bytes: usize,
+    pub filename: &'a str,
+    pub direction: Direction,
+    pub complete: bool,
+}
+
+/// Format record to one log line, ends with '\n'
+fn format_record(timestamp: u64, record: &TransferRecord) -> String {
+    let filename = record.filename.replace(' ', "_");
+    let direction = match record.direction {
+        Direction::Outgoing => 'o',
+    };
+    let status = if record.complete { 'c' } else { 'i' };
+    format!(
+        "{timestamp:} {} {} {filename:} {direction:} {status:}\n",
+        record.client_ip, record.bytes
+    )
+}
+
+/// Append record of a transfer finished at `time` to log file at path, the file is created if it doesn't exist
+pub fn append<P: AsRef<Path>>(path: P, time: SystemTime, record: &TransferRecord) -> Result<()> {
+    let timestamp = time.duration_since(UNIX_EPOCH)?.as_secs();
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    // a single write of a whole line, so lines from concurrent sessions don't interleave
+    file.write_all(format_record(timestamp, record).as_bytes())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod access_log_test {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_format_record() {
+        let record = TransferRecord {
+            client_ip: "127.0.0.1".parse().unwrap(),
+            bytes: 42,
+            filename: "/pub/a file.txt",
+            direction: Direction::Outgoing,
+            complete: true,
+        };
+        assert_eq!(
+            format_record(1654000000, &record),
+            "1654000000 127.0.0.1 42 /pub/a_file.txt o c\n"
+        );
+    }
+
+    #[test]
+    fn test_append() {
+        let path = std::env::temp_dir().join(format!("rust-ftp-access-log-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let mut record = TransferRecord {
+            client_ip: "127.0.0.1".parse().unwrap(),
+            bytes: 0,
+            filename: ".",
+            direction: Direction::Outgoing,
+            complete: false,
+        };
+        append(&path, SystemTime::now(), &record).unwrap();
+        record.complete = true;
+        append(&path, SystemTime::now(), &record).unwrap();
+
+        let content = fs::read_to_string(&path).unwrap();
+        let lines = content.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].ends_with("127.0.0.1 0 . o i"));
+        assert!(lines[1].ends_with("127.0.0.1 0 . o c"));
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,28 @@
+//! # audit
+//! This module contains `AuditSink`, a hook receiving structured security events like login attempts,
+//! unlike logging it's meant to be consumed by security monitoring
+
+use std::{fmt::Debug, net::IpAddr};
+
+/// Receives security events of all sessions, every method does nothing by default
+pub trait AuditSink: Send + Sync + Debug {
+    /// a PASS for `username` from `client_ip` was accepted or rejected
+    fn login_attempt(&self, _username: &str, _client_ip: IpAddr, _success: bool) {}
+
+    /// a logged in user ran a command that modifies the served files or runs a program on the server,
+    ///     e.g. DELE, STOR or SITE EXEC, `path` is the command's target (for SITE EXEC the command line)
+    fn privileged_operation(
+        &self,
+        _username: &str,
+        _client_ip: IpAddr,
+        _command: &str,
+        _path: &str,
+    ) {
+    }
+}
+
+/// Drops every event
+#[derive(Debug)]
+pub struct NoopAuditSink;
+
+impl AuditSink for NoopAuditSink {}
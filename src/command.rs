@@ -1,15 +1,15 @@
 #![allow(unused_variables)]
 #![allow(non_snake_case)]
 
-use crate::response;
-use anyhow::{anyhow, Result};
-use std::str::FromStr;
-use strum_macros::EnumString;
+use crate::response::{self, ResponseError};
+use anyhow::Result;
+use std::{borrow::Cow, collections::HashMap, str::FromStr};
+use strum_macros::{EnumIter, EnumString, IntoStaticStr};
 
 macro_rules! commands {
     ($($cmd: ident ($argc: literal)), *) => {
 
-        #[derive(EnumString, Debug)]
+        #[derive(EnumString, EnumIter, IntoStaticStr, Debug)]
         #[strum(ascii_case_insensitive)]
         pub enum Command {
             $(
@@ -22,11 +22,13 @@ macro_rules! commands {
             /// Returns Ok(Command) when command is valid, all arguments will be collected as Strings' vec
             ///     and the length of vec will be equal with the Command's required argument
             ///     if argument is too many, the parse will still be Ok, but if arguments is too less, it will be Err
-            /// Returns `Err(Message)` if command is not valid, Message should be sent to client
+            /// Returns `Err(ResponseError)` if command is not valid, the response should be sent to client
+            /// Arguments are split by any ASCII whitespace, so runs of whitespace collapse and an argument can never
+            ///     be empty or whitespace only: `LIST   ` has no argument, `USER   ` and `PASS ` (an empty password) are 501
             pub fn parse<S: AsRef<str>>(s: S) -> Result<Self> {
                 let tokens = s.as_ref().split_ascii_whitespace().collect::<Vec<_>>();
                 if tokens.is_empty() {
-                   return Err(anyhow!(response::SyntaxErr500::default().to_string()));
+                   return Err(ResponseError::from(response::SyntaxErr500::default()).into());
                 }
 
                 let parse_result = Command::from_str(tokens[0]);
@@ -63,28 +65,80 @@ macro_rules! commands {
 
                                     #[allow(unused_comparisons)]
                                     if args.len() < $argc {
-                                        return Err(anyhow!(response::InvalidParameter501::new("Invalid number of arguments.").to_string()));
+                                        return Err(ResponseError::from(response::InvalidParameter501::new("Invalid number of arguments.")).into());
                                     }
                                     return Ok(Self::$cmd(args));
                                 }
                             )*
                         }
                     },
-                    _ => Err(anyhow!(response::SyntaxErr500::new("Command not understood.").to_string())),
+                    _ if is_known_unimplemented(tokens[0]) => Err(ResponseError::from(response::NotImplementedCommand502::default()).into()),
+                    _ => Err(ResponseError::from(response::SyntaxErr500::new("Command not understood.")).into()),
                 }
             }
 
-            #[allow(dead_code)]
             pub fn get_args(&self) -> &Vec<String> {
                 match self {
                     $(Self::$cmd(v) => &v,)*
                 }
             }
+
+            /// Returns the argument at index, or "" if there's no such argument, never panics
+            ///     a parsed command has all of its `min_args` arguments, a command declared with no argument
+            ///     has its whole optional argument at index 0
+            pub fn arg(&self, index: usize) -> &str {
+                self.get_args().get(index).map_or("", String::as_str)
+            }
+
+            /// Returns the first argument, see `arg`
+            pub fn arg0(&self) -> &str {
+                self.arg(0)
+            }
+
+            /// Returns the name of command, e.g. "Quit"
+            pub fn name(&self) -> &'static str {
+                self.into()
+            }
+
+            /// Returns how many arguments the command requires, a parsed command has at least this many arguments
+            pub fn min_args(&self) -> usize {
+                match self {
+                    $(Self::$cmd(_) => $argc,)*
+                }
+            }
         }
     };
 }
 
-commands!(Quit(0), User(1), Pass(1), FakeCmdWithTwoArg(2), Pasv(0), Port(1), List(0));
+/// Standard FTP commands this server recognizes but doesn't implement, they are answered with 502 rather than 500
+///     so that clients probing for extensions can tell the command is known
+const KNOWN_UNIMPLEMENTED: &[&str] = &["MIC", "CONF", "ENC"];
+
+fn is_known_unimplemented(verb: &str) -> bool {
+    KNOWN_UNIMPLEMENTED.iter().any(|known| known.eq_ignore_ascii_case(verb))
+}
+
+/// Rewrite the verb of line if it's an alias in `aliases` (alias to real verb, case insensitive)
+/// An alias never shadows a real command, and an alias to something that isn't a command is ignored
+pub fn apply_alias<'a>(line: &'a str, aliases: &HashMap<String, String>) -> Cow<'a, str> {
+    let line = line.trim_start();
+    // split like `Command::parse` does, e.g. `DIR\t-la`
+    let (verb, rest) = line.split_once(|c: char| c.is_ascii_whitespace()).unwrap_or((line, ""));
+    if verb.is_empty() || Command::from_str(verb).is_ok() {
+        return Cow::Borrowed(line);
+    }
+    let real_verb = aliases
+        .iter()
+        .find(|(alias, _)| alias.eq_ignore_ascii_case(verb))
+        .map(|(_, real_verb)| real_verb)
+        .filter(|real_verb| Command::from_str(real_verb).is_ok());
+    match real_verb {
+        Some(real_verb) => Cow::Owned(format!("{real_verb:} {rest:}")),
+        None => Cow::Borrowed(line),
+    }
+}
+
+commands!(Quit(0), User(1), Pass(1), FakeCmdWithTwoArg(2), Pasv(0), Spsv(0), Port(1), List(0), Site(1), Feat(0), Noop(0), Stat(0), Clnt(1), Type(1), Host(1), Rein(0), Rest(1));
 
 #[cfg(test)]
 mod command_test {
@@ -142,6 +196,47 @@ mod command_test {
         assert!(err.to_string().starts_with("501"));
     }
 
+    #[test]
+    fn test_parse_whitespace_only_arguments() {
+        // zero-arg commands have no optional argument
+        for line in ["LIST   \r\n", "LIST\t\r\n", "NOOP \r\n"] {
+            assert!(Command::parse(line).unwrap().get_args().is_empty(), "{line:?}");
+        }
+        // one-arg commands lack their argument, an empty password is not a password
+        for line in ["USER   \r\n", "PASS \r\n", "PASS\t \r\n", "TYPE  \r\n"] {
+            let err = Command::parse(line).err().unwrap();
+            assert!(err.to_string().starts_with("501"), "{line:?}");
+        }
+        // surrounding and repeated whitespace is not part of arguments
+        assert_eq!(Command::parse("USER  a \t b \r\n").unwrap().arg0(), "a b");
+    }
+
+    #[test]
+    fn test_arg_accessors() {
+        let user = Command::parse("USER name\r\n").unwrap();
+        assert_eq!(user.arg0(), "name");
+
+        let fake = Command::parse("FakeCmdWithTwoArg a b c\r\n").unwrap();
+        assert_eq!(fake.arg(0), "a");
+        assert_eq!(fake.arg(1), "b c");
+        // past the declared arguments
+        assert_eq!(user.arg(1), "");
+        assert_eq!(fake.arg(2), "");
+
+        // optional argument of commands declared without argument
+        assert_eq!(Command::parse("LIST\r\n").unwrap().arg0(), "");
+        assert_eq!(Command::parse("LIST -la /pub\r\n").unwrap().arg0(), "-la /pub");
+    }
+
+    #[test]
+    fn test_parse_utf8_arguments() {
+        let user = Command::parse("USER 用户😀\r\n").unwrap();
+        assert_eq!(user.get_args()[0], "用户😀");
+
+        let list = Command::parse("LIST -la 目录/😀 文件.txt\r\n").unwrap();
+        assert_eq!(list.get_args()[0], "-la 目录/😀 文件.txt");
+    }
+
     #[test]
     fn test_parse_type() {
         let quit = Command::parse("QUIT\r\n").unwrap();
@@ -154,6 +249,27 @@ mod command_test {
         assert!(matches!(pass, Command::Pass(_)));
     }
 
+    #[test]
+    fn test_apply_alias() {
+        let aliases = HashMap::from([
+            ("DIR".to_string(), "LIST".to_string()),
+            ("USER".to_string(), "LIST".to_string()),
+            ("BAD".to_string(), "NONE".to_string()),
+        ]);
+        assert!(matches!(Command::parse(apply_alias("dir", &aliases)).unwrap(), Command::List(_)));
+        let list = Command::parse(apply_alias("DIR -la /pub", &aliases)).unwrap();
+        assert!(matches!(list, Command::List(_)));
+        assert_eq!(list.get_args()[0], "-la /pub");
+        let list = Command::parse(apply_alias("DIR\t-la", &aliases)).unwrap();
+        assert_eq!(list.arg0(), "-la");
+
+        // real commands can't be shadowed
+        assert!(matches!(Command::parse(apply_alias("USER name", &aliases)).unwrap(), Command::User(_)));
+        // aliases to unknown commands are ignored
+        assert_eq!(apply_alias("BAD arg", &aliases), "BAD arg");
+        assert_eq!(apply_alias("", &aliases), "");
+    }
+
     #[test]
     fn test_parse_syntax_error_or_unexist() {
         let empty_err = Command::parse("\r\n").err().unwrap();
@@ -161,4 +277,20 @@ mod command_test {
         let none_err = Command::parse("NONE arg1 arg2 arg3\r\n").err().unwrap();
         assert!(none_err.to_string().starts_with("500"));
     }
+
+    #[test]
+    fn test_parse_known_unimplemented() {
+        for line in ["MIC abc\r\n", "conf abc\r\n", "ENC\r\n"] {
+            let err = Command::parse(line).err().unwrap();
+            assert!(err.to_string().starts_with("502"), "{line}");
+        }
+    }
+
+    #[test]
+    fn test_parse_error_is_response() {
+        for line in ["\r\n", "NONE\r\n", "USER\r\n"] {
+            let err = Command::parse(line).err().unwrap();
+            assert!(err.downcast_ref::<ResponseError>().is_some());
+        }
+    }
 }
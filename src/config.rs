@@ -0,0 +1,365 @@
+//! # config
+//! This module contains `ServerConfig`, the options shared by every session of a server
+
+use crate::{
+    logging::LogFormat, middleware, response::MessageCatalog, session::TransferType, LISTENING_HOST,
+};
+use anyhow::{anyhow, Result};
+use std::{
+    collections::{HashMap, HashSet},
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// Server-wide options, created once and shared by all sessions
+#[derive(Debug)]
+pub struct ServerConfig {
+    /// Addresses the server accepts control connections on, each is served by its own thread
+    pub listen_addrs: Vec<SocketAddr>,
+    /// Added to the bound passive port to get the port advertised in the 227 reply,
+    ///     for NAT setups (e.g. docker) where the data port range is forwarded to a different range.
+    ///     0 means advertise the bound port
+    pub pasv_port_offset: i32,
+    /// IPv4 address sent in the 227 reply, e.g. the public address behind NAT,
+    ///     None means the server's address of the control connection
+    pub pasv_address: Option<String>,
+    /// Interface the passive data listener binds, independent of the advertised `pasv_address`,
+    ///     None means the wildcard address
+    pub pasv_bind_address: Option<IpAddr>,
+    /// A session can bind at most this many passive listeners within `pasv_rate_window`,
+    ///     further PASV are rejected with 421 until older ones leave the window. 0 means unlimited
+    pub max_pasv_per_window: usize,
+    pub pasv_rate_window: Duration,
+    /// Reject data connections whose source ip differs from the control connection's,
+    ///     so another host can't steal a passive port
+    pub require_matching_data_ip: bool,
+    /// Allow PORT to an ip other than the control connection's,
+    ///     off by default so the server can't be used to connect (bounce) to third parties
+    pub allow_foreign_port_address: bool,
+    /// Local port that active (PORT) data connections are made from, traditionally 20 (`ftp-data`)
+    ///     for firewalls expecting it. Binding ports below 1024 needs privilege, if it fails the connection
+    ///     is made from an ephemeral port instead. None means an ephemeral port
+    pub active_data_source_port: Option<u16>,
+    /// SO_RCVBUF of data connections in bytes, for tuning high-latency high-bandwidth links, None means OS default
+    pub data_recv_buffer_size: Option<usize>,
+    /// SO_SNDBUF of data connections in bytes, None means OS default
+    pub data_send_buffer_size: Option<usize>,
+    /// Append a line for every data transfer to this file, see `access_log` for the format
+    pub access_log_path: Option<PathBuf>,
+    /// Initial umask of every session, can be changed by client with `SITE UMASK`
+    pub umask: u32,
+    /// Close the connection with 421 after this many unparseable commands in a row, 0 means never
+    pub max_consecutive_parse_errors: usize,
+    /// Silently skip empty command lines (some interactive clients send stray CRLFs) instead of replying 500
+    pub ignore_blank_lines: bool,
+    /// Reply 503 to commands sent before the reply to the previous one (pipelining), to diagnose misbehaving clients
+    pub reject_pipelined_commands: bool,
+    /// Hard cap on how long a data command (opening data connection and transferring) may take,
+    ///     None means no limit
+    pub command_timeout: Option<Duration>,
+    /// Once a transfer has run this long, the control connection is checked between reads and writes of the transfer
+    ///     and STAT is answered with the bytes transferred so far, other commands wait until the transfer is done.
+    ///     None means the control connection isn't read during transfers
+    pub stat_during_transfer_after: Option<Duration>,
+    /// Contents of this file are sent as the multi-line part of 230 after a successful login
+    pub login_message_path: Option<PathBuf>,
+    /// Verbs answered with 502 before their handler runs, e.g. `SITE`, case insensitive
+    pub disabled_commands: HashSet<String>,
+    /// Extra verbs accepted for commands, e.g. `DIR` to `LIST`, case insensitive.
+    ///     An alias that is a real command is ignored
+    pub command_aliases: HashMap<String, String>,
+    /// Close control connection with 421 if client sends nothing for this long
+    pub idle_timeout: Duration,
+    /// Upper bound of the idle timeout a client can set for itself with `SITE IDLE`
+    pub max_idle_timeout: Duration,
+    /// Delay the greeting by this long for ips that failed to login within `failed_login_ttl`, zero disables the tarpit
+    pub failed_login_delay: Duration,
+    /// How long a failed login is remembered
+    pub failed_login_ttl: Duration,
+    /// Allow login as `anonymous` or `ftp`
+    pub allow_anonymous: bool,
+    /// How many data transfers a user can run at once over all its connections, 0 means unlimited.
+    ///     Anonymous users are counted by ip since they share one username
+    pub max_transfers_per_user: usize,
+    /// Representation type of a session before any TYPE command. RFC 959 says ASCII,
+    ///     binary deviates from the RFC but matches what most clients expect
+    pub default_transfer_type: TransferType,
+    /// Omit entries starting with `.` from listings, `.` and `..` are still listed
+    pub hide_dotfiles: bool,
+    /// LIST sends at most this many entries followed by a truncation notice line, 0 means unlimited
+    pub max_list_entries: usize,
+    /// DANGER: lets logged in clients run programs on the server with `SITE EXEC`, only for trusted internal setups.
+    ///     Even when enabled, only the exact command lines in `site_exec_allowed` run, everything else gets 550
+    pub site_exec_enabled: bool,
+    /// Command lines SITE EXEC may run, each is the whole argv like `["/usr/bin/uptime"]` and must match the
+    ///     client's whitespace separated words exactly. The program must be an absolute path, it's run directly
+    ///     with an empty environment, never through a shell or looked up in `PATH`
+    pub site_exec_allowed: Vec<Vec<String>>,
+    /// Sites selected by `HOST <name>` before login, keyed by lowercase hostname
+    pub virtual_hosts: HashMap<String, VirtualHost>,
+    /// Greet with the server's name, version and extension commands like `220 rust-ftp 0.1.0 ready (SPSV, FEAT)`
+    ///     instead of the default greeting. Off by default so the software isn't advertised.
+    ///     A `Greeting220` in `message_catalog` is sent instead of the banner
+    pub greeting_advertises_version: bool,
+    /// How accepted connections get a thread to run their session
+    pub threading: ThreadingMode,
+    /// Names of built-in middlewares (see `middleware::by_name`) run on every command in this order,
+    ///     selected with `--middleware`
+    pub builtin_middlewares: Vec<String>,
+    /// Connections over this many (served or waiting for a worker) are rejected before any session state is
+    ///     created, 0 means unlimited
+    pub max_connections: usize,
+    /// Reply code and text sent before closing a connection rejected by `max_connections` or a full worker pool
+    pub too_many_connections_code: u16,
+    pub too_many_connections_message: String,
+    /// Overrides default response messages, keyed by response name like `Greeting220`, see `response::MessageCatalog`
+    pub message_catalog: MessageCatalog,
+    /// Format of the server's log lines, see `logging`
+    pub log_format: LogFormat,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            listen_addrs: vec![format!("{LISTENING_HOST:}:8080").parse().unwrap()],
+            pasv_port_offset: 0,
+            pasv_address: None,
+            pasv_bind_address: None,
+            max_pasv_per_window: 0,
+            pasv_rate_window: Duration::from_secs(10),
+            require_matching_data_ip: false,
+            allow_foreign_port_address: false,
+            active_data_source_port: None,
+            data_recv_buffer_size: None,
+            data_send_buffer_size: None,
+            access_log_path: None,
+            umask: 0o022,
+            max_consecutive_parse_errors: 10,
+            ignore_blank_lines: true,
+            reject_pipelined_commands: false,
+            command_timeout: None,
+            stat_during_transfer_after: None,
+            login_message_path: None,
+            disabled_commands: HashSet::new(),
+            command_aliases: HashMap::new(),
+            idle_timeout: Duration::from_secs(300),
+            max_idle_timeout: Duration::from_secs(7200),
+            failed_login_delay: Duration::ZERO,
+            failed_login_ttl: Duration::from_secs(600),
+            allow_anonymous: true,
+            max_transfers_per_user: 0,
+            default_transfer_type: TransferType::Ascii,
+            hide_dotfiles: false,
+            max_list_entries: 0,
+            site_exec_enabled: false,
+            site_exec_allowed: Vec::new(),
+            virtual_hosts: HashMap::new(),
+            greeting_advertises_version: false,
+            threading: ThreadingMode::SpawnPerConnection,
+            builtin_middlewares: Vec::new(),
+            max_connections: 0,
+            too_many_connections_code: 421,
+            too_many_connections_message: "Too many connections, try again later.".to_string(),
+            message_catalog: MessageCatalog::new(),
+            log_format: LogFormat::Text,
+        }
+    }
+}
+
+/// How the server runs sessions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadingMode {
+    /// a new thread for each connection
+    SpawnPerConnection,
+    /// a fixed pool of `workers` threads, at most `queue_len` connections wait for a free worker
+    ///     and the ones accepted when the queue is full are rejected with 421
+    Pool { workers: usize, queue_len: usize },
+}
+
+/// Options of a name-based virtual host, replacing the server-wide ones for sessions that selected it with HOST
+#[derive(Debug, Clone)]
+pub struct VirtualHost {
+    /// Message of the 220 reply to HOST, None means the default greeting
+    pub banner: Option<String>,
+    /// Replaces `ServerConfig::login_message_path`
+    pub login_message_path: Option<PathBuf>,
+    /// Replaces `ServerConfig::allow_anonymous`
+    pub allow_anonymous: bool,
+}
+
+impl Default for VirtualHost {
+    fn default() -> Self {
+        Self {
+            banner: None,
+            login_message_path: None,
+            allow_anonymous: true,
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Set the options given on the command line, each as `--name=value`:
+    ///
+    /// - `--log-format=text|json`, see `log_format`
+    /// - `--pool=<workers>,<queue_len>` to serve connections by a pool of threads, see `ThreadingMode::Pool`
+    /// - `--middleware=<name>` to run a built-in middleware, can be repeated, see `builtin_middlewares`
+    pub fn apply_args<I, S>(&mut self, args: I) -> Result<()>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        for arg in args {
+            let arg = arg.as_ref();
+            let (name, value) = arg.split_once('=').unwrap_or((arg, ""));
+            match name {
+                "--log-format" => {
+                    self.log_format = match value {
+                        "text" => LogFormat::Text,
+                        "json" => LogFormat::Json,
+                        _ => {
+                            return Err(anyhow!("--log-format must be text or json, not {value:?}"))
+                        }
+                    }
+                }
+                "--pool" => {
+                    let sizes = value.split_once(',').and_then(|(workers, queue_len)| {
+                        Some((workers.parse().ok()?, queue_len.parse().ok()?))
+                    });
+                    self.threading = match sizes {
+                        Some((workers, queue_len)) if workers > 0 => {
+                            ThreadingMode::Pool { workers, queue_len }
+                        }
+                        _ => {
+                            return Err(anyhow!(
+                            "--pool must be <workers>,<queue_len> with workers > 0, not {value:?}"
+                        ))
+                        }
+                    }
+                }
+                "--middleware" => {
+                    if middleware::by_name(value).is_none() {
+                        return Err(anyhow!("unknown middleware {value:?}"));
+                    }
+                    self.builtin_middlewares.push(value.to_string());
+                }
+                _ => return Err(anyhow!("unknown option {arg:}")),
+            }
+        }
+        Ok(())
+    }
+
+    /// Check options that can't be right in any setup, the server refuses to start with them
+    pub fn validate(&self) -> Result<()> {
+        for argv in &self.site_exec_allowed {
+            if !argv
+                .first()
+                .is_some_and(|program| Path::new(program).is_absolute())
+            {
+                return Err(anyhow!(
+                    "site_exec_allowed entry {argv:?} must start with an absolute path"
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the port that should be sent to client for a passive listener bound on `bound_port`
+    /// Returns None if the offset moves the port out of range, port 0 included since clients can't connect to it
+    pub fn pasv_advertised_port(&self, bound_port: u16) -> Option<u16> {
+        u16::try_from(i32::from(bound_port) + self.pasv_port_offset)
+            .ok()
+            .filter(|&port| port != 0)
+    }
+}
+
+#[cfg(test)]
+mod config_test {
+    use super::*;
+
+    #[test]
+    fn test_apply_args() {
+        let mut config = ServerConfig::default();
+        config.apply_args::<_, &str>([]).unwrap();
+        assert_eq!(config.log_format, LogFormat::Text);
+
+        config.apply_args(["--log-format=json"]).unwrap();
+        assert_eq!(config.log_format, LogFormat::Json);
+        config.apply_args(["--log-format=text"]).unwrap();
+        assert_eq!(config.log_format, LogFormat::Text);
+
+        config.apply_args(["--pool=4,16"]).unwrap();
+        assert_eq!(
+            config.threading,
+            ThreadingMode::Pool {
+                workers: 4,
+                queue_len: 16
+            }
+        );
+
+        config
+            .apply_args(["--middleware=block-list-during-business-hours"])
+            .unwrap();
+        assert_eq!(
+            config.builtin_middlewares,
+            ["block-list-during-business-hours"]
+        );
+
+        for args in [
+            ["--middleware=none"],
+            ["--log-format=xml"],
+            ["--log-format"],
+            ["--verbose"],
+            ["--pool=4"],
+            ["--pool=0,16"],
+            ["--pool=a,b"],
+        ] {
+            assert!(config.apply_args(args).is_err(), "{args:?}");
+        }
+    }
+
+    #[test]
+    fn test_validate_site_exec_allowed() {
+        assert!(ServerConfig::default().validate().is_ok());
+
+        let config = ServerConfig {
+            site_exec_allowed: vec![vec!["/usr/bin/uptime".into()]],
+            ..Default::default()
+        };
+        assert!(config.validate().is_ok());
+
+        for argv in [
+            vec!["uptime".to_string()],
+            vec!["./uptime".to_string()],
+            vec![],
+        ] {
+            let config = ServerConfig {
+                site_exec_allowed: vec![argv.clone()],
+                ..Default::default()
+            };
+            assert!(config.validate().is_err(), "{argv:?}");
+        }
+    }
+
+    #[test]
+    fn test_pasv_advertised_port_default() {
+        let config = ServerConfig::default();
+        assert_eq!(config.pasv_advertised_port(30000), Some(30000));
+    }
+
+    #[test]
+    fn test_pasv_advertised_port_offset() {
+        let config = ServerConfig {
+            pasv_port_offset: 10000,
+            ..Default::default()
+        };
+        assert_eq!(config.pasv_advertised_port(30000), Some(40000));
+
+        let config = ServerConfig {
+            pasv_port_offset: -10000,
+            ..Default::default()
+        };
+        assert_eq!(config.pasv_advertised_port(30000), Some(20000));
+        assert_eq!(config.pasv_advertised_port(5000), None);
+    }
+}
@@ -0,0 +1,85 @@
+//! # context
+//! This module contains `ServerContext`, the config and the services shared by all sessions of a server,
+//! it's created once when the server starts and cloned into every session
+
+use crate::{
+    audit::{AuditSink, NoopAuditSink},
+    clock::{Clock, SystemClock},
+    config::ServerConfig,
+    metrics::Metrics,
+    middleware::{self, Middleware},
+    response::MessageCatalog,
+    tarpit::Tarpit,
+    transfer_limit::TransferLimit,
+};
+use std::sync::Arc;
+
+#[derive(Clone)]
+pub struct ServerContext {
+    pub config: Arc<ServerConfig>,
+    /// ips that failed to login recently
+    pub tarpit: Arc<Tarpit>,
+    /// running transfers of each user
+    pub transfer_limit: Arc<TransferLimit>,
+    /// wall clock time of timestamps
+    pub clock: Arc<dyn Clock>,
+    pub metrics: Arc<Metrics>,
+    /// replaces default messages of replies, from `ServerConfig::message_catalog`
+    pub catalog: Arc<MessageCatalog>,
+    /// run in order on every command before its handler, see `middleware`
+    pub middlewares: Arc<Vec<Middleware>>,
+    /// receives login attempts and privileged operations of all sessions, see `audit`
+    pub audit_sink: Arc<dyn AuditSink>,
+}
+
+impl ServerContext {
+    /// create the services configured by config
+    pub fn new(config: Arc<ServerConfig>) -> Self {
+        Self {
+            tarpit: Arc::new(Tarpit::new(config.failed_login_ttl)),
+            transfer_limit: Arc::new(TransferLimit::new(config.max_transfers_per_user)),
+            clock: Arc::new(SystemClock),
+            metrics: Arc::new(Metrics::default()),
+            catalog: Arc::new(config.message_catalog.clone()),
+            middlewares: Arc::new(
+                config
+                    .builtin_middlewares
+                    .iter()
+                    .filter_map(|name| middleware::by_name(name))
+                    .collect(),
+            ),
+            audit_sink: Arc::new(NoopAuditSink),
+            config,
+        }
+    }
+}
+
+#[cfg(test)]
+mod context_test {
+    use super::*;
+
+    #[test]
+    fn test_clones_share_services() {
+        let context = ServerContext::new(Arc::new(ServerConfig::default()));
+        let cloned = context.clone();
+        let ip = "10.0.0.1".parse().unwrap();
+
+        context.tarpit.record_failure(ip);
+        assert!(cloned.tarpit.is_recent_offender(ip));
+        assert!(Arc::ptr_eq(&context.config, &cloned.config));
+        assert!(Arc::ptr_eq(&context.transfer_limit, &cloned.transfer_limit));
+        assert!(Arc::ptr_eq(&context.catalog, &cloned.catalog));
+    }
+
+    #[test]
+    fn test_builtin_middlewares() {
+        let context = ServerContext::new(Arc::new(ServerConfig {
+            builtin_middlewares: vec!["block-list-during-business-hours".to_string()],
+            ..Default::default()
+        }));
+        assert_eq!(context.middlewares.len(), 1);
+        assert!(ServerContext::new(Arc::new(ServerConfig::default()))
+            .middlewares
+            .is_empty());
+    }
+}
@@ -0,0 +1,104 @@
+//! # data_stream
+//! This module contains `DataStream`, the data connection handed to transfer logic,
+//! it counts the bytes going through it so the session can report progress while transferring,
+//! and calls back between reads and writes so the session can serve the control connection meanwhile
+
+use std::{
+    io::{self, Read, Write},
+    net::TcpStream,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+pub struct DataStream<'a> {
+    stream: &'a mut TcpStream,
+    /// bytes read and written so far, shared with whoever reports the progress
+    progress: Arc<AtomicUsize>,
+    /// called after every successful read or write
+    poll: Option<&'a mut dyn FnMut()>,
+}
+
+impl<'a> DataStream<'a> {
+    pub fn new(stream: &'a mut TcpStream, progress: Arc<AtomicUsize>) -> Self {
+        Self {
+            stream,
+            progress,
+            poll: None,
+        }
+    }
+
+    /// call poll after every successful read or write, it must not block for long since the transfer waits for it
+    pub fn with_poll(mut self, poll: &'a mut dyn FnMut()) -> Self {
+        self.poll = Some(poll);
+        self
+    }
+
+    fn moved(&mut self, len: usize) {
+        self.progress.fetch_add(len, Ordering::Relaxed);
+        if let Some(poll) = &mut self.poll {
+            poll();
+        }
+    }
+}
+
+impl Read for DataStream<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.stream.read(buf)?;
+        self.moved(len);
+        Ok(len)
+    }
+}
+
+impl Write for DataStream<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.stream.write(buf)?;
+        self.moved(len);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+#[cfg(test)]
+mod data_stream_test {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_progress() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+        let progress = Arc::new(AtomicUsize::new(0));
+
+        let mut stream = DataStream::new(&mut server, progress.clone());
+        stream.write_all(b"hello").unwrap();
+        assert_eq!(progress.load(Ordering::Relaxed), 5);
+
+        client.write_all(b"abc").unwrap();
+        let mut buf = [0; 3];
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"abc");
+        assert_eq!(progress.load(Ordering::Relaxed), 8);
+    }
+
+    #[test]
+    fn test_poll() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+        let mut polls = 0;
+        let mut poll = || polls += 1;
+
+        let mut stream =
+            DataStream::new(&mut server, Arc::new(AtomicUsize::new(0))).with_poll(&mut poll);
+        stream.write_all(b"hello").unwrap();
+        stream.write_all(b"world").unwrap();
+        drop(stream);
+        assert_eq!(polls, 2);
+    }
+}
@@ -0,0 +1,43 @@
+//! # host_port
+//! This module contains the port encoding of PASV reply and PORT argument (`h1,h2,h3,h4,p1,p2`),
+//! where the port is `p1 * 256 + p2`
+
+/// from port to (p1, p2)
+pub fn encode_pasv_port(port: u16) -> (u8, u8) {
+    let [p1, p2] = port.to_be_bytes();
+    (p1, p2)
+}
+
+/// from (p1, p2) to port
+pub fn decode_pasv_port(p1: u8, p2: u8) -> u16 {
+    u16::from_be_bytes([p1, p2])
+}
+
+#[cfg(test)]
+mod host_port_test {
+    use super::*;
+
+    #[test]
+    fn test_encode_pasv_port() {
+        assert_eq!(encode_pasv_port(0), (0, 0));
+        assert_eq!(encode_pasv_port(255), (0, 255));
+        assert_eq!(encode_pasv_port(256), (1, 0));
+        assert_eq!(encode_pasv_port(1025), (4, 1));
+        assert_eq!(encode_pasv_port(u16::MAX), (255, 255));
+    }
+
+    #[test]
+    fn test_decode_pasv_port() {
+        assert_eq!(decode_pasv_port(0, 255), 255);
+        assert_eq!(decode_pasv_port(1, 0), 256);
+        assert_eq!(decode_pasv_port(4, 1), 1025);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for port in 0..=u16::MAX {
+            let (p1, p2) = encode_pasv_port(port);
+            assert_eq!(decode_pasv_port(p1, p2), port);
+        }
+    }
+}
@@ -0,0 +1,196 @@
+//! # logging
+//! This module contains the setup of the process wide logger, which writes either env_logger's
+//! human readable text or one JSON object per line for log pipelines:
+//!
+//! `{"timestamp":"2022-06-01T12:00:00.000Z","level":"DEBUG","session":3,"client":"127.0.0.1:50000","command":"LIST","code":226,"message":"..."}`
+//!
+//! - `session` and `client` are the id and client address of the session the line was logged by,
+//!   so lines of concurrent sessions can be correlated. Text lines of a session are prefixed by `session <id>:`
+//! - `command` is the command being executed and `code` the code of the reply being sent, both only when there's one
+//!
+//! A session runs on one thread at a time, so the session fields are kept per thread, see `set_session`
+
+use crate::clock::format_timestamp;
+use env_logger::Env;
+use log::Record;
+use std::{cell::RefCell, io::Write, time::SystemTime};
+
+/// Format of lines written by the logger, selected with `--log-format`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// env_logger's default format
+    Text,
+    /// one JSON object per line
+    Json,
+}
+
+/// Session fields of lines logged by the current thread
+#[derive(Default)]
+struct LogContext {
+    session: Option<u64>,
+    client: Option<String>,
+    command: Option<String>,
+    code: Option<u16>,
+}
+
+thread_local! {
+    static CONTEXT: RefCell<LogContext> = RefCell::new(LogContext::default());
+}
+
+/// Initialize the logger, `RUST_LOG` selects the levels and defaults to debug
+pub fn init(format: LogFormat) {
+    let mut builder = env_logger::Builder::from_env(Env::default().default_filter_or("debug"));
+    match format {
+        LogFormat::Text => builder.format(|buf, record| {
+            let session = CONTEXT.with(|context| context.borrow().session);
+            let prefix = session.map_or(String::new(), |id| format!("session {id:}: "));
+            writeln!(
+                buf,
+                "[{} {:<5} {}] {prefix:}{}",
+                buf.timestamp(),
+                buf.default_styled_level(record.level()),
+                record.target(),
+                record.args()
+            )
+        }),
+        LogFormat::Json => builder.format(|buf, record| {
+            let line =
+                CONTEXT.with(|context| format_json(SystemTime::now(), record, &context.borrow()));
+            writeln!(buf, "{line:}")
+        }),
+    };
+    builder.init();
+}
+
+/// Mark lines logged by the current thread as logged by the session with `id` and `client`,
+///     None when the session ends
+pub fn set_session(session: Option<(u64, String)>) {
+    let (session, client) = session.unzip();
+    CONTEXT.with(|context| {
+        *context.borrow_mut() = LogContext {
+            session,
+            client,
+            ..Default::default()
+        }
+    });
+}
+
+/// Mark lines logged by the current thread as logged while executing `command`, None when it's done
+pub fn set_command(command: Option<String>) {
+    CONTEXT.with(|context| context.borrow_mut().command = command);
+}
+
+/// Run `f` with lines it logs marked as logged while sending a reply of `code`
+pub fn with_reply_code<R>(code: Option<u16>, f: impl FnOnce() -> R) -> R {
+    CONTEXT.with(|context| context.borrow_mut().code = code);
+    let result = f();
+    CONTEXT.with(|context| context.borrow_mut().code = None);
+    result
+}
+
+/// Format record logged at `time` to one JSON object, without line ending
+fn format_json(time: SystemTime, record: &Record, context: &LogContext) -> String {
+    let mut line = format!(
+        "{{\"timestamp\":\"{}\",\"level\":\"{}\"",
+        format_timestamp(time),
+        record.level()
+    );
+    if let Some(session) = context.session {
+        line += &format!(",\"session\":{session:}");
+    }
+    if let Some(client) = &context.client {
+        line += &format!(",\"client\":\"{}\"", escape_json(client));
+    }
+    if let Some(command) = &context.command {
+        line += &format!(",\"command\":\"{}\"", escape_json(command));
+    }
+    if let Some(code) = context.code {
+        line += &format!(",\"code\":{code:}");
+    }
+    line + &format!(
+        ",\"message\":\"{}\"}}",
+        escape_json(&record.args().to_string())
+    )
+}
+
+/// Escape s to be put between double quotes of a JSON string
+fn escape_json(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if c < ' ' => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod logging_test {
+    use super::*;
+    use log::Level;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn format_with(context: &LogContext, message: &str) -> String {
+        let time = UNIX_EPOCH + Duration::from_millis(1654084800123);
+        format_json(
+            time,
+            &Record::builder()
+                .level(Level::Debug)
+                .args(format_args!("{message:}"))
+                .build(),
+            context,
+        )
+    }
+
+    #[test]
+    fn test_format_json() {
+        let context = LogContext {
+            session: Some(3),
+            client: Some("127.0.0.1:50000".to_string()),
+            command: Some("LIST".to_string()),
+            code: Some(226),
+        };
+        assert_eq!(
+            format_with(&context, "Send message: 226 Transfer complete."),
+            "{\"timestamp\":\"2022-06-01T12:00:00.123Z\",\"level\":\"DEBUG\",\"session\":3,\"client\":\"127.0.0.1:50000\",\
+             \"command\":\"LIST\",\"code\":226,\"message\":\"Send message: 226 Transfer complete.\"}"
+        );
+    }
+
+    #[test]
+    fn test_format_json_without_session() {
+        assert_eq!(
+            format_with(&LogContext::default(), "Starting server"),
+            "{\"timestamp\":\"2022-06-01T12:00:00.123Z\",\"level\":\"DEBUG\",\"message\":\"Starting server\"}"
+        );
+    }
+
+    #[test]
+    fn test_escape_json() {
+        assert_eq!(
+            escape_json("a \"b\" \\ c\r\n\u{1}"),
+            "a \\\"b\\\" \\\\ c\\r\\n\\u0001"
+        );
+        assert_eq!(escape_json("目录😀"), "目录😀");
+    }
+
+    #[test]
+    fn test_context_is_per_session() {
+        set_session(Some((1, "127.0.0.1:1".to_string())));
+        set_command(Some("LIST".to_string()));
+        with_reply_code(Some(150), || {
+            CONTEXT.with(|context| assert_eq!(context.borrow().code, Some(150)))
+        });
+        CONTEXT.with(|context| assert_eq!(context.borrow().code, None));
+        // a new session on the thread starts without the previous command
+        set_session(Some((2, "127.0.0.1:2".to_string())));
+        CONTEXT.with(|context| assert!(context.borrow().command.is_none()));
+        set_session(None);
+    }
+}
@@ -1,33 +1,141 @@
+mod access_log;
+mod audit;
+mod clock;
 mod command;
+mod config;
+mod context;
+mod data_stream;
+mod host_port;
+mod logging;
+mod metrics;
+mod middleware;
 mod response;
+mod self_test;
 mod session;
+mod site_exec;
+mod socket_opts;
+mod tarpit;
+mod telnet;
+mod test_client;
+mod transfer_limit;
+mod worker_pool;
 use std::{
-    net::{TcpListener, TcpStream, ToSocketAddrs},
+    io::Write,
+    net::{TcpListener, TcpStream},
+    sync::Arc,
     thread,
 };
 
-use anyhow::Result;
-use env_logger::Env;
+use anyhow::{anyhow, Result};
+use config::{ServerConfig, ThreadingMode};
+use context::ServerContext;
 use log::{debug, error, info};
+use metrics::ConnectionSlot;
+use response::{MessageCatalog, ResponseMessage};
 use session::Session;
+use worker_pool::WorkerPool;
 
 static LISTENING_HOST: &str = "0.0.0.0";
 
 fn main() {
-    env_logger::Builder::from_env(Env::default().default_filter_or("debug")).init();
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    let self_test = args.iter().any(|arg| arg == "--self-test");
+    let mut config = ServerConfig::default();
+    if let Err(e) = config.apply_args(args.iter().filter(|arg| *arg != "--self-test")) {
+        // the logger isn't set up before its format is known
+        eprintln!("{e:}");
+        std::process::exit(2);
+    }
+    let config = Arc::new(config);
+    logging::init(config.log_format);
+
+    if self_test {
+        match self_test::run() {
+            Ok(()) => info!("Self test passed"),
+            Err(e) => {
+                error!("Self test failed: {e:}");
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
 
-    let addr = LISTENING_HOST.to_owned() + ":" + "8080";
-    info!("Starting server at {addr:}");
-    serve(addr);
+    if let Err(e) = config.validate() {
+        error!("Invalid config: {e:}");
+        std::process::exit(1);
+    }
+    serve(config);
 }
 
-fn serve<A: ToSocketAddrs>(addr: A) {
-    let listener = TcpListener::bind(addr).unwrap();
+/// listen on every address in `config.listen_addrs`, each address is accepted in its own thread
+///     and all sessions share the same config and tarpit
+fn serve(config: Arc<ServerConfig>) {
+    let listeners = config
+        .listen_addrs
+        .iter()
+        .map(|addr| {
+            info!("Starting server at {addr:}");
+            TcpListener::bind(addr).unwrap()
+        })
+        .collect::<Vec<_>>();
+    serve_listeners(listeners, config);
+}
+
+/// a connection waiting for a worker of the pool, counted until it's served
+type Connection = (TcpStream, ServerContext, ConnectionSlot);
+
+/// accept clients on already bound listeners, each in its own thread
+///     all sessions share one `ServerContext`
+fn serve_listeners(listeners: Vec<TcpListener>, config: Arc<ServerConfig>) {
+    let pool = match config.threading {
+        ThreadingMode::SpawnPerConnection => None,
+        ThreadingMode::Pool { workers, queue_len } => Some(Arc::new(WorkerPool::new(
+            workers,
+            queue_len,
+            |(stream, context, _slot): Connection| serve_one_client(stream, context),
+        ))),
+    };
+    let context = ServerContext::new(config);
+    let acceptors = listeners
+        .into_iter()
+        .map(|listener| {
+            let context = context.clone();
+            let pool = pool.clone();
+            thread::spawn(move || accept_clients(listener, context, pool))
+        })
+        .collect::<Vec<_>>();
+    for acceptor in acceptors {
+        let _ = acceptor.join();
+    }
+}
+
+/// serve every accepted client in a new thread, or in a worker of pool if there's one
+///     clients over `max_connections` are rejected right away, without a thread or a session
+fn accept_clients(listener: TcpListener, context: ServerContext, pool: Option<Arc<WorkerPool<Connection>>>) {
     for stream in listener.incoming() {
         match stream {
             Ok(stream) => {
-                serve_one_client(stream);
-            }
+                let Some(slot) = context.metrics.try_track_connection(context.config.max_connections) else {
+                    info!("Already {} connections, rejected {:?}", context.config.max_connections, stream.peer_addr());
+                    reject_connection(stream, &context.config);
+                    continue;
+                };
+                match &pool {
+                    None => {
+                        let context = context.clone();
+                        thread::spawn(move || {
+                            let _slot = slot;
+                            serve_one_client(stream, context)
+                        });
+                    }
+                    Some(pool) => {
+                        if let Err((stream, _, _)) = pool.try_submit((stream, context.clone(), slot)) {
+                            info!("All workers are busy and the queue is full, rejected {:?}", stream.peer_addr());
+                            reject_connection(stream, &context.config);
+                        }
+                    }
+                }
+            },
             Err(e) => {
                 error!("failed accepting client's connection: {e:}");
             }
@@ -35,94 +143,110 @@ fn serve<A: ToSocketAddrs>(addr: A) {
     }
 }
 
-/// handle client with a infinite loop, read client's command and exec it
-fn serve_one_client(stream: TcpStream) {
+/// send the configured too many connections reply, the connection is closed when stream is dropped
+fn reject_connection(mut stream: TcpStream, config: &ServerConfig) {
+    let reply = format!("{} {}\r\n", config.too_many_connections_code, config.too_many_connections_message);
+    if let Err(e) = stream.write_all(reply.as_bytes()) {
+        debug!("Failed sending too many connections reply: {e:}");
+    }
+}
+
+/// handle client with a infinite loop, read client's command and exec it, returns when the session ends
+fn serve_one_client(stream: TcpStream, context: ServerContext) {
     let client_addr = stream
         .peer_addr()
         .map_or("unknown".to_string(), |v| v.to_string());
 
-    thread::spawn(move || {
-        if let Ok(mut session) = Session::new(stream) {
-            let mut run = || -> Result<()> {
-                info!("Session with {client_addr:} starts");
-                session.send_msg_check_crlf(response::Greeting220::default())?;
-
-                loop {
-                    let cmd = session.get_cmd()?;
-                    debug!("Parse result: {cmd:?}");
-                    match cmd {
-                        Ok(cmd) => {
-                            let resp = session.exec_cmd(cmd)?;
-                            session.send_msg_check_crlf(resp)?;
-                        },
-                        Err(e) => {
-                            session.send_msg_check_crlf(e.to_string())?;
+    let catalog = context.catalog.clone();
+    let max_parse_errors = context.config.max_consecutive_parse_errors;
+    let failed_login_delay = context.config.failed_login_delay;
+    let tarpit = context.tarpit.clone();
+    if let Ok(mut session) = Session::new(stream, context) {
+        let id = session.id();
+        logging::set_session(Some((id, client_addr.clone())));
+        let mut run = || -> Result<()> {
+            if !failed_login_delay.is_zero() && tarpit.is_recent_offender(session.client_ip()) {
+                info!("{client_addr:} failed to login recently, delaying greeting for {failed_login_delay:?}");
+                thread::sleep(failed_login_delay);
+            }
+            info!("Session {id:} with {client_addr:} starts");
+            let greeting = session.greeting();
+            session.send_msg_check_crlf(greeting)?;
+
+            let mut parse_errors = 0;
+            loop {
+                let cmd = session.get_cmd()?;
+                debug!("Parse result: {cmd:?}");
+                match cmd {
+                    Ok(cmd) => {
+                        parse_errors = 0;
+                        let resp = session.exec_cmd(cmd)?;
+                        session.send_msg_check_crlf(resp)?;
+                    },
+                    // a command rejected for being pipelined was valid, so it doesn't count as a parse error
+                    Err(e) if session.rejected_pipelined() => {
+                        session.send_msg_check_crlf(parse_error_response(&e, &catalog))?;
+                    },
+                    Err(e) => {
+                        parse_errors += 1;
+                        if parse_errors == max_parse_errors {
+                            session.send_msg_check_crlf(response::ServiceNotAvalible421::new("Too many invalid commands, closing control connection."))?;
+                            return Err(anyhow!("{parse_errors:} invalid commands in a row"));
                         }
+                        session.send_msg_check_crlf(parse_error_response(&e, &catalog))?;
                     }
                 }
-            };
-            if let Err(e) = run() {
-                info!("Session with {client_addr:} closed: {e:}");
             }
-        } else {
-            error!("Error creating session with {client_addr:}");
+        };
+        if let Err(e) = run() {
+            info!("Session {id:} with {client_addr:} closed: {e:}");
         }
-    });
+        logging::set_session(None);
+    } else {
+        error!("Error creating session with {client_addr:}");
+    }
+}
+
+/// Returns the response of a failed parse with catalog applied, errors that aren't a `ResponseError` are logged
+///     and reported as a generic 500 so internal error text is never sent to client
+fn parse_error_response(e: &anyhow::Error, catalog: &MessageCatalog) -> String {
+    match e.downcast_ref::<response::ResponseError>() {
+        Some(resp) => resp.render(catalog),
+        None => {
+            error!("Failed parsing command: {e:}");
+            response::SyntaxErr500::default().render(catalog)
+        }
+    }
 }
 
 #[cfg(test)]
 pub mod integration_test {
     use std::{
-        io::{BufRead, BufReader, BufWriter, Write},
+        io::{BufRead, BufReader, BufWriter},
         net::TcpStream,
         sync::Once,
         thread::{self, sleep},
         time::Duration,
     };
 
-    use anyhow::{anyhow, Result};
+    use anyhow::anyhow;
     use log::info;
 
-    use crate::{response::*, serve};
+    use crate::{config::ServerConfig, response::*, serve};
 
-    pub struct TestClient {
-        pub(crate) cmd_reader: BufReader<TcpStream>,
-        pub(crate) cmd_writer: BufWriter<TcpStream>,
-    }
+    pub use crate::test_client::TestClient;
 
     pub const USERNAME: &str = "anonymous";
     pub const PASSWORD: &str = "anonymous";
 
-    impl TestClient {
-        /// receive one line message from server and trim it
-        pub fn get_msg_trimed(&mut self) -> Result<String> {
-            let mut line = String::new();
-            let bytes = self.cmd_reader.read_line(&mut line).unwrap();
-            if bytes == 0 {
-                return Err(anyhow!(""));
-            }
-            Ok(line.trim().to_string())
-        }
-
-        pub fn get_msg_code(&mut self) -> Result<u16> {
-            let msg = self.get_msg_trimed()?;
-            Ok(msg.split_ascii_whitespace().next().unwrap().parse().unwrap())
-        }
-
-        /// send one line message to server(with appended \r\n)
-        pub fn send_msg_add_crlf(&mut self, msg: &str) -> Result<()> {
-            self.cmd_writer
-                .write_all(format!("{msg:}\r\n").as_bytes())?;
-            self.cmd_writer.flush()?;
-            Ok(())
-        }
-    }
-
     mod setup {
         use super::*;
         use crate::LISTENING_HOST;
+        use std::sync::Arc;
         
         const TEST_PORT: u16 = 8080;
+        /// the server also listens on this port, only on loopback address
+        pub const SECOND_TEST_PORT: u16 = 8081;
 
         static INIT: Once = Once::new();
         fn setup_once() {
@@ -138,17 +262,35 @@ pub mod integration_test {
 
         fn setup_server() {
             let _server = thread::spawn(move || {
-                serve(format!("{LISTENING_HOST:}:{TEST_PORT:}"));
+                serve(Arc::new(ServerConfig {
+                    listen_addrs: vec![
+                        format!("{LISTENING_HOST:}:{TEST_PORT:}").parse().unwrap(),
+                        format!("127.0.0.1:{SECOND_TEST_PORT:}").parse().unwrap(),
+                    ],
+                    command_aliases: [("DIR".to_string(), "LIST".to_string())].into(),
+                    ..Default::default()
+                }));
             });
-            // wait server to start
-            sleep(Duration::from_micros(100));
-            info!("server is up");
+            // clients wait for the listeners in setup_client_on
+            info!("server is starting");
         }
 
         /// returns reader/writer of control conn
         pub fn setup_client() -> TestClient {
+            setup_client_on(TEST_PORT)
+        }
+
+        /// returns reader/writer of control conn connected to the server's `port`
+        pub fn setup_client_on(port: u16) -> TestClient {
             setup_once();
-            let client = TcpStream::connect(format!("127.0.0.1:{TEST_PORT:}")).unwrap();
+            // the server may still be binding its listeners
+            let client = (0..100)
+                .find_map(|_| {
+                    TcpStream::connect(format!("127.0.0.1:{port:}"))
+                        .map_err(|_| sleep(Duration::from_millis(10)))
+                        .ok()
+                })
+                .unwrap();
             let cmd_reader = BufReader::new(client.try_clone().unwrap());
             let cmd_writer = BufWriter::new(client.try_clone().unwrap());
             info!("client is up");
@@ -162,13 +304,7 @@ pub mod integration_test {
             let mut client = setup_client();
 
             client.get_msg_trimed().unwrap();
-    
-            client.send_msg_add_crlf(&format!("USER {USERNAME:}")).unwrap();
-            assert_eq!(client.get_msg_code().unwrap(), 331);
-    
-            client.send_msg_add_crlf(&format!("PASS {PASSWORD:}")).unwrap();
-            assert_eq!(client.get_msg_code().unwrap(), 230);
-
+            client.converse(&[(&format!("USER {USERNAME:}"), 331), (&format!("PASS {PASSWORD:}"), 230)]).unwrap();
             client
         }
     }
@@ -181,22 +317,7 @@ pub mod integration_test {
         }
 
         pub fn parse_pasv_response(s: &str) -> String {
-            let mut split = s.split_ascii_whitespace();
-            split.next();
-            let pasv_part = split.next().unwrap();
-            let pasv = &pasv_part[1..pasv_part.len()-1]; // (..)
-            let splited_pasv = pasv.split(',').collect::<Vec<_>>();
-            println!("{s:} {:?}", splited_pasv);
-            let h1 = splited_pasv[0];
-            let h2 = splited_pasv[1];
-            let h3 = splited_pasv[2];
-            let h4 = splited_pasv[3];
-            let p1 = splited_pasv[4];
-            let p2 = splited_pasv[5];
-            let port: u16 = p1.parse::<u16>().unwrap() * 256 + p2.parse::<u16>().unwrap();
-            format!(
-                "{h1:}.{h2:}.{h3:}.{h4:}:{port:}"
-            )
+            crate::test_client::parse_pasv_response(s).unwrap()
         }
 
         pub fn data_conn_to_pasv_response(s: &str) -> TcpStream {
@@ -243,58 +364,243 @@ pub mod integration_test {
     fn test_login_success() {
         let mut client = setup_client();
 
-        client.get_msg_trimed().unwrap();
+        client.expect_response("greeting", 220).unwrap();
+        client.converse(&[(&format!("USER {USERNAME:}"), 331), (&format!("PASS {PASSWORD:}"), 230)]).unwrap();
+    }
 
-        client.send_msg_add_crlf(&format!("USER {USERNAME:}")).unwrap();
-        assert_eq!(client.get_msg_code().unwrap(), 331);
+    #[test]
+    fn test_login_fail() {
+        let mut client = setup_client();
 
-        client.send_msg_add_crlf(&format!("PASS {PASSWORD:}")).unwrap();
-        assert_eq!(client.get_msg_code().unwrap(), 230);
+        client.expect_response("greeting", 220).unwrap();
+        client
+            .converse(&[(&format!("USER {USERNAME:}"), 331), ("PASS wrong", 530), (&format!("PASS {PASSWORD:}"), 503)])
+            .unwrap();
     }
 
     #[test]
-    fn test_login_fail() {
+    fn test_permission() {
         let mut client = setup_client();
 
-        client.get_msg_trimed().unwrap();
+        client.expect_response("greeting", 220).unwrap();
+        client.converse(&[("LIST", 530), ("PASV", 530)]).unwrap();
+    }
 
+    #[test]
+    fn test_list_pasv() {
+        let mut client = setup_client_login();
+
+        client.expect("LIST", 425).unwrap();
+        let pasv_resp = client.expect("PASV", 227).unwrap();
+
+        let _ = BufReader::new(data_conn_to_pasv_response(&pasv_resp));
+        client.expect("LIST", 150).unwrap();
+        client.expect_response("LIST", 226).unwrap();
+    }
+
+    #[test]
+    fn test_list_with_flags_and_path() {
+        let mut client = setup_client_login();
+
+        for list in ["LIST", "LIST -la", "LIST /pub", "LIST -la /pub"] {
+            client.send_msg_add_crlf("PASV").unwrap();
+            let pasv_resp = client.get_msg_trimed().unwrap();
+            assert!(pasv_resp.starts_with("227"));
+
+            let _ = BufReader::new(data_conn_to_pasv_response(&pasv_resp));
+            client.send_msg_add_crlf(list).unwrap();
+            assert_eq!(client.get_msg_code().unwrap(), 150, "{list}");
+            assert_eq!(client.get_msg_code().unwrap(), 226, "{list}");
+        }
+    }
+
+    #[test]
+    fn test_too_many_invalid_commands() {
+        let mut client = setup_client();
+
+        client.get_msg_trimed().unwrap();
+        let max_parse_errors = ServerConfig::default().max_consecutive_parse_errors;
+        for _ in 1..max_parse_errors {
+            client.send_msg_add_crlf("NONE").unwrap();
+            assert_eq!(client.get_msg_code().unwrap(), 500);
+        }
+        // a valid command resets the counter
         client.send_msg_add_crlf(&format!("USER {USERNAME:}")).unwrap();
         assert_eq!(client.get_msg_code().unwrap(), 331);
+        for _ in 1..max_parse_errors {
+            client.send_msg_add_crlf("NONE").unwrap();
+            assert_eq!(client.get_msg_code().unwrap(), 500);
+        }
 
-        client.send_msg_add_crlf("PASS wrong").unwrap();
-        assert_eq!(client.get_msg_code().unwrap(), 530);
-
-        client.send_msg_add_crlf(&format!("PASS {PASSWORD:}")).unwrap();
-        assert_eq!(client.get_msg_code().unwrap(), 503);
+        client.send_msg_add_crlf("NONE").unwrap();
+        assert_eq!(client.get_msg_code().unwrap(), 421);
+        assert!(client.get_msg_trimed().is_err()); // conn should close
     }
 
     #[test]
-    fn test_permission() {
+    fn test_invalid_utf8_command() {
+        use std::io::Write;
         let mut client = setup_client();
 
         client.get_msg_trimed().unwrap();
+        client.cmd_writer.write_all(b"NOOP \xc3\x28\r\n").unwrap();
+        client.cmd_writer.flush().unwrap();
+        assert_eq!(client.get_msg_code().unwrap(), 500);
+        // the session is kept
+        client.send_msg_add_crlf("NOOP").unwrap();
+        assert_eq!(client.get_msg_code().unwrap(), 200);
+    }
 
-        client.send_msg_add_crlf("LIST").unwrap();
-        assert_eq!(client.get_msg_code().unwrap(), 530);
+    #[test]
+    fn test_pipelined_commands_are_not_parse_errors() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = std::sync::Arc::new(ServerConfig {
+            listen_addrs: vec![addr],
+            max_consecutive_parse_errors: 3,
+            reject_pipelined_commands: true,
+            ..Default::default()
+        });
+        thread::spawn(move || crate::serve_listeners(vec![listener], config));
+
+        let mut client = TestClient::connect(addr).unwrap();
+        client.expect_response("greeting", 220).unwrap();
+        // sent in one write, all but the first one are read while there's more buffered
+        client.send_msg_add_crlf(&["NOOP"; 10].join("\r\n")).unwrap();
+        let codes = (0..10).map(|_| client.get_msg_code().unwrap()).collect::<Vec<_>>();
+        assert_eq!(codes[0], 200);
+        assert!(codes[1..].iter().all(|code| *code == 503), "{codes:?}");
+        client.expect("NOOP", 200).unwrap();
+        client.expect("QUIT", 221).unwrap();
+    }
 
-        client.send_msg_add_crlf("PASV").unwrap();
-        assert_eq!(client.get_msg_code().unwrap(), 530);
+    #[test]
+    fn test_second_listen_addr() {
+        let mut client = setup_client_on(SECOND_TEST_PORT);
+
+        client.expect_response("greeting", 220).unwrap();
+        client.expect("QUIT", 221).unwrap();
     }
 
     #[test]
-    fn test_list_pasv() {
+    fn test_list_utf8_path() {
         let mut client = setup_client_login();
 
-        client.send_msg_add_crlf("LIST").unwrap();
-        assert_eq!(client.get_msg_code().unwrap(), 425);
-
         client.send_msg_add_crlf("PASV").unwrap();
         let pasv_resp = client.get_msg_trimed().unwrap();
-        assert!(pasv_resp.starts_with("227"));
-
         let _ = BufReader::new(data_conn_to_pasv_response(&pasv_resp));
-        client.send_msg_add_crlf("LIST").unwrap();
+        client.send_msg_add_crlf("LIST -la 目录/😀 文件.txt").unwrap();
+        assert_eq!(client.get_msg_code().unwrap(), 150);
+        assert_eq!(client.get_msg_code().unwrap(), 226);
+    }
+
+    #[test]
+    fn test_list_alias() {
+        let mut client = setup_client_login();
+
+        client.send_msg_add_crlf("PASV").unwrap();
+        let pasv_resp = client.get_msg_trimed().unwrap();
+        let mut data_reader = BufReader::new(data_conn_to_pasv_response(&pasv_resp));
+        client.send_msg_add_crlf("DIR").unwrap();
         assert_eq!(client.get_msg_code().unwrap(), 150);
-        assert_eq!(client.get_msg_code().unwrap(), 226); 
+        assert_eq!(client.get_msg_code().unwrap(), 226);
+
+        let mut listing = String::new();
+        data_reader.read_line(&mut listing).unwrap();
+        assert_string_trim_eq(listing, ".");
+    }
+
+    #[test]
+    fn test_feat_full_response() {
+        let mut client = setup_client();
+        client.get_msg_trimed().unwrap();
+
+        client.send_msg_add_crlf("FEAT").unwrap();
+        let (code, lines) = client.get_full_response().unwrap();
+        assert_eq!(code, 211);
+        assert_eq!(lines.first().unwrap(), "Features:");
+        assert!(lines.iter().any(|line| line == "SITE UMASK"), "{lines:?}");
+        assert_eq!(lines.last().unwrap(), "End");
+
+        // the whole reply is consumed
+        client.send_msg_add_crlf("NOOP").unwrap();
+        assert_eq!(client.get_full_response().unwrap(), (200, vec!["NOOP ok.".to_string()]));
+    }
+
+    #[test]
+    fn test_worker_pool_rejects_when_full() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = std::sync::Arc::new(ServerConfig {
+            listen_addrs: vec![addr],
+            threading: crate::config::ThreadingMode::Pool { workers: 1, queue_len: 1 },
+            ..Default::default()
+        });
+        thread::spawn(move || crate::serve_listeners(vec![listener], config));
+
+        // the only worker serves first, second waits in the queue
+        let mut first = TestClient::connect(addr).unwrap();
+        first.expect_response("greeting", 220).unwrap();
+        let mut second = TestClient::connect(addr).unwrap();
+        let mut third = TestClient::connect(addr).unwrap();
+        assert_eq!(third.get_msg_code().unwrap(), 421);
+        assert!(third.get_msg_trimed().is_err());
+
+        first.expect("QUIT", 221).unwrap();
+        second.expect_response("greeting", 220).unwrap();
+    }
+
+    #[test]
+    fn test_max_connections() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = std::sync::Arc::new(ServerConfig {
+            listen_addrs: vec![addr],
+            max_connections: 1,
+            too_many_connections_code: 421,
+            too_many_connections_message: "Server full, try again later".to_string(),
+            ..Default::default()
+        });
+        thread::spawn(move || crate::serve_listeners(vec![listener], config));
+
+        let mut first = TestClient::connect(addr).unwrap();
+        first.expect_response("greeting", 220).unwrap();
+        let mut second = TestClient::connect(addr).unwrap();
+        assert_eq!(second.get_msg_trimed().unwrap(), "421 Server full, try again later");
+        assert!(second.get_msg_trimed().is_err());
+
+        // the slot is free once the first session ends
+        first.expect("QUIT", 221).unwrap();
+        drop(first);
+        let mut third = (0..100)
+            .map(|_| {
+                let mut client = TestClient::connect(addr).unwrap();
+                let code = client.get_msg_code().unwrap();
+                sleep(Duration::from_millis(10));
+                (client, code)
+            })
+            .find(|(_, code)| *code == 220)
+            .unwrap()
+            .0;
+        third.expect("QUIT", 221).unwrap();
+    }
+
+    #[test]
+    fn test_parse_error_response() {
+        let catalog = crate::MessageCatalog::new();
+        let err = crate::command::Command::parse("USER").err().unwrap();
+        assert!(crate::parse_error_response(&err, &catalog).starts_with("501"));
+
+        let internal_err = anyhow!("stream did not contain valid UTF-8");
+        assert_string_trim_eq(
+            crate::parse_error_response(&internal_err, &catalog),
+            SyntaxErr500::default().to_string(),
+        );
+
+        // default messages come from the catalog
+        let catalog = crate::MessageCatalog::from([("SyntaxErr500".to_string(), "Erreur.".to_string())]);
+        assert_string_trim_eq(crate::parse_error_response(&internal_err, &catalog), "500 Erreur.");
+        let err = crate::command::Command::parse("").err().unwrap();
+        assert_string_trim_eq(crate::parse_error_response(&err, &catalog), "500 Erreur.");
     }
 }
@@ -0,0 +1,121 @@
+//! # metrics
+//! This module contains `Metrics`, counters of the whole server shared by all sessions
+
+use std::{
+    net::TcpListener,
+    ops::Deref,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    /// passive listeners bound and not yet closed
+    active_pasv_listeners: AtomicUsize,
+    /// accepted connections that are served or waiting for a worker
+    active_connections: AtomicUsize,
+}
+
+impl Metrics {
+    #[cfg(test)]
+    pub fn active_pasv_listeners(&self) -> usize {
+        self.active_pasv_listeners.load(Ordering::Relaxed)
+    }
+
+    /// count listener as active until the returned `PasvListener` is dropped
+    pub fn track_pasv_listener(self: &Arc<Self>, listener: TcpListener) -> PasvListener {
+        self.active_pasv_listeners.fetch_add(1, Ordering::Relaxed);
+        PasvListener {
+            listener,
+            metrics: self.clone(),
+        }
+    }
+
+    /// count one more connection if there're less than `max` (0 means unlimited) of them,
+    ///     returns None when it's over the limit
+    pub fn try_track_connection(self: &Arc<Self>, max: usize) -> Option<ConnectionSlot> {
+        let previous =
+            self.active_connections
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |active| {
+                    (max == 0 || active < max).then_some(active + 1)
+                });
+        previous.ok().map(|_| ConnectionSlot {
+            metrics: self.clone(),
+        })
+    }
+}
+
+/// An accepted connection counted in `Metrics::active_connections` until it's dropped
+#[derive(Debug)]
+pub struct ConnectionSlot {
+    metrics: Arc<Metrics>,
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.metrics
+            .active_connections
+            .fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// A passive data listener counted in `Metrics::active_pasv_listeners`
+#[derive(Debug)]
+pub struct PasvListener {
+    listener: TcpListener,
+    metrics: Arc<Metrics>,
+}
+
+impl Deref for PasvListener {
+    type Target = TcpListener;
+
+    fn deref(&self) -> &TcpListener {
+        &self.listener
+    }
+}
+
+impl Drop for PasvListener {
+    fn drop(&mut self) {
+        self.metrics
+            .active_pasv_listeners
+            .fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod metrics_test {
+    use super::*;
+
+    #[test]
+    fn test_track_pasv_listener() {
+        let metrics = Arc::new(Metrics::default());
+
+        let first = metrics.track_pasv_listener(TcpListener::bind("127.0.0.1:0").unwrap());
+        let second = metrics.track_pasv_listener(TcpListener::bind("127.0.0.1:0").unwrap());
+        assert_eq!(metrics.active_pasv_listeners(), 2);
+        assert!(first.local_addr().is_ok());
+        drop(first);
+        assert_eq!(metrics.active_pasv_listeners(), 1);
+        drop(second);
+        assert_eq!(metrics.active_pasv_listeners(), 0);
+    }
+
+    #[test]
+    fn test_try_track_connection() {
+        let metrics = Arc::new(Metrics::default());
+
+        let first = metrics.try_track_connection(2).unwrap();
+        let _second = metrics.try_track_connection(2).unwrap();
+        assert!(metrics.try_track_connection(2).is_none());
+        drop(first);
+        assert!(metrics.try_track_connection(2).is_some());
+
+        // unlimited
+        let slots = (0..10)
+            .map(|_| metrics.try_track_connection(0).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(slots.len(), 10);
+    }
+}
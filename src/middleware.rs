@@ -0,0 +1,89 @@
+//! # middleware
+//! This module contains `Middleware`, hooks run on every command before its built-in handler,
+//! so that embedders can intercept or veto commands without changing the handlers.
+//!
+//! **Middlewares run after the login check**: a command that needs login gets 530 from a user not
+//! logged in without any middleware seeing it, only the commands allowed before login (USER, PASS,
+//! QUIT, FEAT, NOOP...) reach middlewares in that state. Disabled commands get 502 before that too.
+//!
+//! Middlewares are listed in `ServerContext::middlewares` and run in order, the first one returning
+//! `Some(reply)` short-circuits: the reply is sent and neither later middlewares nor the handler run.
+//! The ones in this module are enabled by name, e.g. `--middleware=block-list-during-business-hours`
+
+use crate::{command::Command, response, session::Session};
+use std::time::UNIX_EPOCH;
+
+pub type Middleware = fn(&mut Session, &Command) -> Option<String>;
+
+/// Middlewares that can be run by name, see `ServerConfig::builtin_middlewares`
+const BUILTIN: &[(&str, Middleware)] = &[(
+    "block-list-during-business-hours",
+    block_list_during_business_hours,
+)];
+
+/// The built-in middleware called name
+pub fn by_name(name: &str) -> Option<Middleware> {
+    BUILTIN
+        .iter()
+        .find(|(builtin, _)| *builtin == name)
+        .map(|(_, middleware)| *middleware)
+}
+
+/// UTC hours [start, end) during which `block_list_during_business_hours` rejects LIST
+const BUSINESS_HOURS: (u64, u64) = (9, 17);
+
+/// Example middleware: rejects LIST with 450 from 09:00 to 17:00 UTC, e.g. to keep large listings off peak hours
+pub fn block_list_during_business_hours(session: &mut Session, cmd: &Command) -> Option<String> {
+    if !matches!(cmd, Command::List(_)) {
+        return None;
+    }
+    let secs = session
+        .clock()
+        .now()
+        .duration_since(UNIX_EPOCH)
+        .ok()?
+        .as_secs();
+    let hour = secs / 3600 % 24;
+    if (BUSINESS_HOURS.0..BUSINESS_HOURS.1).contains(&hour) {
+        return Some(
+            response::ActionNotTaken450::new("LIST is not available during business hours.")
+                .to_string(),
+        );
+    }
+    None
+}
+
+#[cfg(test)]
+mod middleware_test {
+    use super::*;
+    use crate::{clock::FixedClock, config::ServerConfig};
+    use std::{
+        io,
+        sync::Arc,
+        time::{Duration, SystemTime},
+    };
+
+    fn at_hour(hour: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(1654041600 + hour * 3600)
+    }
+
+    #[test]
+    fn test_block_list_during_business_hours() {
+        let mut session =
+            Session::with_streams(io::empty(), io::sink(), Arc::new(ServerConfig::default()));
+        let list = Command::List(vec![]);
+
+        session.set_clock(Arc::new(FixedClock(at_hour(10))));
+        let resp = block_list_during_business_hours(&mut session, &list).unwrap();
+        assert!(resp.starts_with("450"), "{resp}");
+        assert!(block_list_during_business_hours(&mut session, &Command::Noop(vec![])).is_none());
+
+        for hour in [8, 17, 23] {
+            session.set_clock(Arc::new(FixedClock(at_hour(hour))));
+            assert!(
+                block_list_during_business_hours(&mut session, &list).is_none(),
+                "{hour}"
+            );
+        }
+    }
+}
@@ -2,13 +2,89 @@
 //! This module contains
 //! 1. A trait `ResponseMessage` that describes messages that server reply to client
 //! 2. All message structs that impl `ResponseMessage`
+//! 3. `ResponseError`, an error that should be reported to client as a response
+//! 4. `MessageCatalog`, messages that replace the default messages of responses
+//!
+//! `Display` renders the built-in messages, a catalog is only applied when it's passed to `render`/`with_lines_in`
 
-use std::fmt::Display;
+use std::{collections::HashMap, fmt::Display};
+
+/// Messages replacing default messages of responses, keyed by response name like `Greeting220`.
+///     Responses created with a custom message are not affected
+pub type MessageCatalog = HashMap<String, String>;
+
+/// An error carrying the response that should be sent to client,
+///     so the client never sees the text of other (internal) errors
+#[derive(Debug)]
+pub struct ResponseError {
+    code: u16,
+    message: String,
+    catalog_key: Option<&'static str>,
+}
+
+impl ResponseError {
+    /// The response with catalog applied
+    pub fn render(&self, catalog: &MessageCatalog) -> String {
+        let message = self.catalog_key.and_then(|key| catalog.get(key)).unwrap_or(&self.message);
+        format!("{} {message:}\r\n", self.code)
+    }
+}
+
+impl Display for ResponseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}\r\n", self.code, self.message)
+    }
+}
+
+impl std::error::Error for ResponseError {}
+
+impl<T: ResponseMessage> From<T> for ResponseError {
+    fn from(resp: T) -> Self {
+        Self {
+            code: resp.code(),
+            message: resp.message(&MessageCatalog::new()).to_string(),
+            catalog_key: resp.catalog_key(),
+        }
+    }
+}
 
 /// all response has a response code and a message
 pub trait ResponseMessage: Sized + Display {
     fn code(&self) -> u16;
-    fn message(&self) -> &str;
+
+    /// The custom message if there's one, else the default message
+    fn builtin_message(&self) -> &str;
+
+    /// Name of the response in a catalog, None if it has a custom message, which a catalog never replaces
+    fn catalog_key(&self) -> Option<&'static str> {
+        None
+    }
+
+    /// The message with catalog applied
+    fn message<'a>(&'a self, catalog: &'a MessageCatalog) -> &'a str {
+        self.catalog_key()
+            .and_then(|key| catalog.get(key))
+            .map_or(self.builtin_message(), String::as_str)
+    }
+
+    /// Format as a one line response with catalog applied
+    fn render(&self, catalog: &MessageCatalog) -> String {
+        format!("{} {}\r\n", self.code(), self.message(catalog))
+    }
+
+    /// Format as a multi-line response, each of `lines` is sent as `code-line` before the final `code message` line
+    fn with_lines<S: AsRef<str>>(&self, lines: &[S]) -> String {
+        self.with_lines_in(&MessageCatalog::new(), lines)
+    }
+
+    /// `with_lines` with catalog applied to the final line
+    fn with_lines_in<S: AsRef<str>>(&self, catalog: &MessageCatalog, lines: &[S]) -> String {
+        let mut resp = String::new();
+        for line in lines {
+            resp += &format!("{}-{}\r\n", self.code(), line.as_ref());
+        }
+        resp + &self.render(catalog)
+    }
 }
 
 macro_rules! impl_display {
@@ -16,7 +92,7 @@ macro_rules! impl_display {
         $(
             impl Display for $structname {
                 fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-                    write!(f, "{} {}\r\n", self.code(), self.message())
+                    write!(f, "{} {}\r\n", self.code(), self.builtin_message())
                 }
             }
         )*
@@ -31,7 +107,7 @@ macro_rules! response {
             fn code(&self) -> u16 {
                 $code
             }
-            fn message(&self) -> &str {
+            fn builtin_message(&self) -> &str {
                 &self.0
             }
         }
@@ -51,12 +127,15 @@ macro_rules! response {
             fn code(&self) -> u16 {
                 $code
             }
-            fn message(&self) -> &str {
+            fn builtin_message(&self) -> &str {
                 if let Some(s) = &self.0 {
                     return s;
                 }
                 $default_message
             }
+            fn catalog_key(&self) -> Option<&'static str> {
+                self.0.is_none().then_some(stringify!($structname))
+            }
         }
 
         impl $structname {
@@ -76,6 +155,8 @@ macro_rules! response {
 }
 
 response!(DataTransferStarts150, 150, "150 Here comes the data.");
+response!(CommandOkay200, 200, "Command okay.");
+response!(SystemStatus211, 211, "End");
 response!(Greeting220, 220, "Welcome to the rust FTP Server.");
 response!(Goodbye221, 221, "Goodbye.");
 response!(DataTransferFinished226, 226, "Data transfer finished.");
@@ -83,15 +164,22 @@ response!(PasvMode227, 227);
 response!(LoginSuccess230, 230, "Login successful.");
 
 response!(NeedPassword331, 331, "Please specify the password.");
+response!(PendingFurtherInfo350, 350, "Requested file action pending further information.");
 
 response!(ServiceNotAvalible421, 421, "Service not available, closing control connection.");
 response!(NoModeSpecified425, 425, "Use PASV first.");
+response!(CannotOpenDataConnection425, 425, "Can't open data connection.");
+response!(TransferAborted426, 426, "Connection closed; transfer aborted.");
+response!(ActionNotTaken450, 450, "Requested file action not taken.");
 
 response!(SyntaxErr500, 500, "Command not executed: syntax error.");
 response!(InvalidParameter501, 501, "Invalid parameters.");
 response!(NotImplementedCommand502, 502, "Command not implemented.");
 response!(WrongCmdSequence503, 503, "Wrong command sequence.");
+response!(ParameterNotImplemented504, 504, "Command not implemented for that parameter.");
+response!(NetworkProtocolNotSupported522, 522, "Network protocol not supported.");
 response!(NotLoggedin530, 530, "Please login with USER and PASS.");
+response!(PermissionDenied550, 550, "Permission denied.");
 response!(UnknownRespWithoutDefaultMessage999, 999);
 
 #[cfg(test)]
@@ -111,6 +199,56 @@ mod response_test {
         );
     }
 
+    #[test]
+    fn test_resp_multiline() {
+        assert_eq!(
+            LoginSuccess230::default().with_lines(&["Welcome", "", "Have fun"]),
+            "230-Welcome\r\n230-\r\n230-Have fun\r\n230 Login successful.\r\n"
+        );
+        assert_eq!(
+            LoginSuccess230::default().with_lines::<&str>(&[]),
+            LoginSuccess230::default().to_string()
+        );
+    }
+
+    #[test]
+    fn test_resp_utf8_message() {
+        assert_response_equal_str(
+            UnknownRespWithoutDefaultMessage999::new("\"目录/😀\" created"),
+            "999 \"目录/😀\" created",
+        );
+        assert_eq!(
+            CommandOkay200::default().with_lines(&["文件😀"]),
+            "200-文件😀\r\n200 Command okay.\r\n"
+        );
+    }
+
+    #[test]
+    fn test_resp_catalog() {
+        let catalog = MessageCatalog::from([("Greeting220".to_string(), "Bienvenue.".to_string())]);
+        assert_eq!(Greeting220::default().message(&catalog), "Bienvenue.");
+        assert_eq!(Greeting220::default().render(&catalog), "220 Bienvenue.\r\n");
+        assert_eq!(
+            Greeting220::default().with_lines_in(&catalog, &["Hello"]),
+            "220-Hello\r\n220 Bienvenue.\r\n"
+        );
+        // custom message and responses not in catalog are not affected
+        assert_eq!(Greeting220::new("Hi.").render(&catalog), "220 Hi.\r\n");
+        assert_eq!(Goodbye221::default().render(&catalog), "221 Goodbye.\r\n");
+        // Display is always the built-in message
+        assert_response_equal_str(Greeting220::default(), "220 Welcome to the rust FTP Server.");
+    }
+
+    #[test]
+    fn test_resp_error_catalog() {
+        let catalog = MessageCatalog::from([("SyntaxErr500".to_string(), "Erreur de syntaxe.".to_string())]);
+        let err = ResponseError::from(SyntaxErr500::default());
+        assert_eq!(err.to_string(), "500 Command not executed: syntax error.\r\n");
+        assert_eq!(err.render(&catalog), "500 Erreur de syntaxe.\r\n");
+        let err = ResponseError::from(SyntaxErr500::new("Command not understood."));
+        assert_eq!(err.render(&catalog), "500 Command not understood.\r\n");
+    }
+
     #[test]
     fn test_resp_custom_message() {
         assert_response_equal_str(
@@ -0,0 +1,58 @@
+//! # self_test
+//! `rust-ftp --self-test` starts the server on an ephemeral loopback port and runs a scripted session
+//! (login, PASV, LIST, NOOP, QUIT) against it, so operators can check a deployment
+
+use crate::{
+    config::ServerConfig,
+    serve_listeners,
+    test_client::{parse_pasv_response, TestClient},
+};
+use anyhow::{anyhow, Result};
+use std::{
+    io::Read,
+    net::{TcpListener, TcpStream},
+    sync::Arc,
+    thread,
+};
+
+const USERNAME: &str = "anonymous";
+const PASSWORD: &str = "anonymous";
+
+/// Returns Err describing the first step that didn't get the expected response
+pub fn run() -> Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let config = Arc::new(ServerConfig {
+        listen_addrs: vec![addr],
+        ..Default::default()
+    });
+    thread::spawn(move || serve_listeners(vec![listener], config));
+
+    let mut client = TestClient::connect(addr)?;
+    client.expect_response("greeting", 220)?;
+    client.converse(&[
+        (&format!("USER {USERNAME:}"), 331),
+        (&format!("PASS {PASSWORD:}"), 230),
+    ])?;
+
+    let pasv_resp = client.expect("PASV", 227)?;
+    let data_addr = parse_pasv_response(&pasv_resp)
+        .ok_or_else(|| anyhow!("PASV: malformed reply {pasv_resp:}"))?;
+    let mut data_conn = TcpStream::connect(data_addr)?;
+    client.expect("LIST", 150)?;
+    data_conn.read_to_end(&mut Vec::new())?;
+    client.expect_response("LIST", 226)?;
+
+    client.converse(&[("NOOP", 200), ("QUIT", 221)])?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod self_test_test {
+    use super::*;
+
+    #[test]
+    fn test_self_test() {
+        run().unwrap();
+    }
+}
@@ -1,31 +1,213 @@
 use crate::{
-    command::Command,
-    response::{self},
+    access_log::{self, Direction, TransferRecord},
+    audit::AuditSink,
+    clock::{civil_from_days, Clock},
+    command::{apply_alias, Command},
+    config::{ServerConfig, VirtualHost},
+    context::ServerContext,
+    data_stream::DataStream,
+    host_port::{decode_pasv_port, encode_pasv_port},
+    logging,
+    metrics::{Metrics, PasvListener},
+    middleware::Middleware,
+    response::{self, MessageCatalog, ResponseMessage},
+    site_exec,
+    socket_opts::{self, Buffer},
+    tarpit::Tarpit,
+    telnet::strip_telnet_commands,
+    transfer_limit::TransferLimit,
     LISTENING_HOST
 };
 use anyhow::{anyhow, Result};
-use log::{error, debug};
+use log::{error, debug, warn};
 use paste::paste;
+use strum::IntoEnumIterator;
 use std::{
+    collections::VecDeque,
     fmt::Display,
-    io::{BufRead, BufReader, BufWriter, Write},
-    net::{TcpListener, TcpStream},
+    io::{self, BufRead, BufReader, BufWriter, ErrorKind, Read, Write},
+    net::{IpAddr, Ipv4Addr, Shutdown, SocketAddr, SocketAddrV4, TcpListener, TcpStream},
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 const FAKE_USER: &str = "anonymous";
 const FAKE_PASS: &str = "anonymous";
+/// How many recent commands a session remembers for STAT
+const HISTORY_LEN: usize = 8;
 
 fn fake_user_valid(username: &str, password: &str) -> bool {
     username == FAKE_USER && password == FAKE_PASS
 }
 
-fn get_local_hostname<'a>() -> &'a str {
-    "127.0.0.1"
+/// `anonymous` and `ftp` are the conventional anonymous users
+fn is_anonymous_user(username: &str) -> bool {
+    username.eq_ignore_ascii_case("anonymous") || username.eq_ignore_ascii_case("ftp")
 }
 
 /// from h1.h2.h3.h4 to h1,h2,h3,h4
-fn hostname_to_comma_hostname(hostname: &str) -> String {
-    return hostname.split('.').collect::<Vec<_>>().join(",");
+/// Returns None if address isn't an ipv4 address, since PASV reply can only carry four octets
+fn address_to_comma_address(address: &str) -> Option<String> {
+    let [h1, h2, h3, h4] = address.parse::<Ipv4Addr>().ok()?.octets();
+    Some(format!("{h1:},{h2:},{h3:},{h4:}"))
+}
+
+/// from h1,h2,h3,h4,p1,p2 to the address h1.h2.h3.h4:(p1*256+p2)
+/// Returns None if the argument is malformed
+fn parse_port_argument(arg: &str) -> Option<SocketAddrV4> {
+    let numbers = arg
+        .split(',')
+        .map(|s| s.trim().parse::<u8>())
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .ok()?;
+    if numbers.len() != 6 {
+        return None;
+    }
+    let ip = Ipv4Addr::new(numbers[0], numbers[1], numbers[2], numbers[3]);
+    let port = decode_pasv_port(numbers[4], numbers[5]);
+    Some(SocketAddrV4::new(ip, port))
+}
+
+/// Clients send `ls` flags like `LIST -la /pub`, returns the path in LIST's argument with a leading flags token removed
+/// Returns "." if there's no path
+fn list_path(arg: &str) -> &str {
+    let arg = arg.trim();
+    let path = match arg.split_once(' ') {
+        Some((flags, rest)) if is_list_flags(flags) => rest.trim_start(),
+        None if is_list_flags(arg) => "",
+        _ => arg,
+    };
+    if path.is_empty() {
+        "."
+    } else {
+        path
+    }
+}
+
+/// `-` followed by letters, like `-l`, `-la` or `-alR`
+fn is_list_flags(token: &str) -> bool {
+    token
+        .strip_prefix('-')
+        .is_some_and(|flags| !flags.is_empty() && flags.chars().all(|c| c.is_ascii_alphabetic()))
+}
+
+/// entries of a directory that are listed, dotfiles (other than `.` and `..`) are dropped if `hide_dotfiles`
+fn visible_entries<'a>(entries: &[&'a str], hide_dotfiles: bool) -> Vec<&'a str> {
+    entries
+        .iter()
+        .copied()
+        .filter(|entry| !hide_dotfiles || !entry.starts_with('.') || *entry == "." || *entry == "..")
+        .collect()
+}
+
+/// entries of a listing, one per CRLF terminated line
+/// if there're more than `max_entries` (0 means unlimited) entries, only the first `max_entries` are listed followed by a notice line
+fn format_listing(entries: &[&str], max_entries: usize) -> String {
+    if max_entries == 0 || entries.len() <= max_entries {
+        return entries.iter().map(|entry| format!("{entry:}\r\n")).collect();
+    }
+    let listed = entries[..max_entries].iter().map(|entry| format!("{entry:}\r\n")).collect::<String>();
+    listed + &format!("... listing truncated after {max_entries:} of {} entries\r\n", entries.len())
+}
+
+/// one entry in the DOS (IIS) listing format, `MM-DD-YY  HH:MMAM       <DIR>          name` for directories
+///     and `MM-DD-YY  HH:MMAM                 size name` for files, the time is in UTC
+fn format_dos_entry(name: &str, is_dir: bool, size: u64, modified: SystemTime) -> String {
+    let secs = modified.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()) as i64;
+    let (year, month, day) = civil_from_days(secs.div_euclid(86400));
+    let minutes_of_day = secs.rem_euclid(86400) / 60;
+    let (hour, minute) = (minutes_of_day / 60, minutes_of_day % 60);
+    let (hour12, am_pm) = match hour {
+        0 => (12, "AM"),
+        1..=11 => (hour, "AM"),
+        12 => (12, "PM"),
+        _ => (hour - 12, "PM"),
+    };
+    let size_or_dir = if is_dir { format!("{:<22}", "       <DIR>") } else { format!("{size:>21} ") };
+    format!("{month:02}-{day:02}-{:02}  {hour12:02}:{minute:02}{am_pm:}{size_or_dir:}{name:}", year.rem_euclid(100))
+}
+
+/// terminate every line of text (ended by CRLF or LF) with the line ending of transfer_type
+fn normalize_line_endings(text: &str, transfer_type: TransferType) -> String {
+    text.lines().map(|line| format!("{line:}{}", transfer_type.line_ending())).collect()
+}
+
+/// time left before deadline, None if there's no deadline
+fn time_left(deadline: Option<Instant>) -> Option<Duration> {
+    deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()))
+}
+
+/// call f again while it's interrupted by a signal (EINTR)
+fn retry_interrupted<T>(mut f: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+    loop {
+        match f() {
+            Err(e) if e.kind() == ErrorKind::Interrupted => debug!("Interrupted, retrying: {e:}"),
+            result => return result,
+        }
+    }
+}
+
+/// accept a connection from listener, fails with `ErrorKind::TimedOut` if nobody connects before deadline
+/// an accept interrupted by a signal is retried, only the deadline gives up
+fn accept_before(listener: &TcpListener, deadline: Option<Instant>) -> io::Result<(TcpStream, SocketAddr)> {
+    if deadline.is_none() {
+        return retry_interrupted(|| listener.accept());
+    }
+    listener.set_nonblocking(true)?;
+    loop {
+        match retry_interrupted(|| listener.accept()) {
+            Ok((stream, addr)) => {
+                stream.set_nonblocking(false)?;
+                return Ok((stream, addr));
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                if time_left(deadline) == Some(Duration::ZERO) {
+                    return Err(io::Error::new(ErrorKind::TimedOut, "accept timed out"));
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// how cmd is shown in command history, e.g. `LIST -la /pub`, the password of PASS is redacted
+fn history_entry(cmd: &Command) -> String {
+    let name = cmd.name().to_ascii_uppercase();
+    match cmd {
+        Command::Pass(_) => format!("{name:} ****"),
+        _ if cmd.get_args().is_empty() => name,
+        _ => format!("{name:} {}", cmd.get_args().join(" ")),
+    }
+}
+
+/// how long SITE EXEC waits for a program if `command_timeout` is unset
+const SITE_EXEC_TIMEOUT: Duration = Duration::from_secs(10);
+/// bytes of stdout and of stderr of a SITE EXEC program that are kept
+const SITE_EXEC_MAX_OUTPUT: usize = 16 * 1024;
+
+/// how often the control connection is checked during a transfer, see `stat_during_transfer_after`
+const CONTROL_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// whether err is caused by a socket read/write timeout
+fn is_timeout(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<io::Error>()
+        .is_some_and(|e| matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut))
+}
+
+/// whether err is caused by the peer closing or resetting a connection, e.g. a client cancelling a listing
+fn is_connection_dropped(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<io::Error>().is_some_and(|e| {
+        matches!(
+            e.kind(),
+            ErrorKind::BrokenPipe | ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted | ErrorKind::UnexpectedEof
+        )
+    })
 }
 
 #[derive(PartialEq, Debug)]
@@ -38,59 +220,278 @@ enum LoginStatus {
 #[derive(Debug)]
 enum TransferMode {
     NotSpecified,
-    Pasv(u16, TcpListener),
+    Pasv(u16, PasvListener),
+    Port(SocketAddrV4),
+}
+
+/// Representation type of transferred data, set by TYPE
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TransferType {
+    Ascii,
+    Binary,
+}
+
+impl TransferType {
+    fn name(self) -> &'static str {
+        match self {
+            TransferType::Ascii => "ASCII",
+            TransferType::Binary => "BINARY",
+        }
+    }
+
+    /// line terminator of text sent in this type, CRLF for ASCII as RFC 959 requires, LF for binary
+    fn line_ending(self) -> &'static str {
+        match self {
+            TransferType::Ascii => "\r\n",
+            TransferType::Binary => "\n",
+        }
+    }
+}
+
+/// Dialect of LIST output, set by `SITE LISTFMT`
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ListFormat {
+    /// one name per line
+    Unix,
+    /// DOS/Windows (IIS) style, with date, time and `<DIR>` or size, for legacy Windows clients
+    Dos,
+}
+
+/// What the transfer logic of a data command moved
+#[derive(Debug, Default, PartialEq)]
+struct TransferResult {
+    bytes: usize,
+}
+
+/// The control connection shared by reader and writer of a session, so the socket doesn't need to be cloned
+#[derive(Clone)]
+struct SharedStream(Arc<TcpStream>);
+
+impl Read for SharedStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&*self.0).read(buf)
+    }
+}
+
+impl Write for SharedStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self.0).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self.0).flush()
+    }
 }
 
+/// Id of the next session, ids are unique within the process
+static NEXT_SESSION_ID: AtomicU64 = AtomicU64::new(1);
+
 /// Session with a client
 pub struct Session {
-    cmd_reader: BufReader<TcpStream>,
-    cmd_writer: BufWriter<TcpStream>,
+    /// unique id to tell sessions apart in logs
+    id: u64,
+    cmd_reader: BufReader<Box<dyn Read + Send>>,
+    cmd_writer: BufWriter<Box<dyn Write + Send>>,
+    /// the last command read was followed by more buffered input, see `reject_pipelined_commands`
+    pipelined: bool,
+    /// the last command read was rejected as pipelined, it isn't a parse error
+    rejected_pipelined: bool,
+    /// the control connection for socket options, None if the session runs over other streams
+    cmd_stream: Option<Arc<TcpStream>>,
+    client_addr: SocketAddr,
+    /// local address of the control connection
+    server_addr: SocketAddr,
     login_status: LoginStatus,
     transfer_mode: TransferMode,
-}
-
-macro_rules! check_permission_or_return {
-    ($self: ident) => {
-        match $self.login_status {
-            LoginStatus::Username(_) | LoginStatus::Unloggedin => {
-                debug!("User not logged in.");
-                return Ok(response::NotLoggedin530::default().to_string());
-            },
-            _ => {}
-        };
-    };
+    /// when each passive listener within `pasv_rate_window` was bound, oldest first
+    pasv_times: VecDeque<Instant>,
+    transfer_type: TransferType,
+    list_format: ListFormat,
+    /// offset set by REST for the command right after it
+    restart_offset: u64,
+    umask: u32,
+    idle_timeout: Duration,
+    /// the last `HISTORY_LEN` commands, oldest first
+    history: VecDeque<String>,
+    /// software the client identified itself as with CLNT
+    client_name: Option<String>,
+    /// the site selected with HOST
+    virtual_host: Option<VirtualHost>,
+    /// filename of the transfer in progress
+    transferring: Option<String>,
+    /// bytes moved by the transfer in progress, shared with the transfer's `DataStream`
+    transfer_progress: Arc<AtomicUsize>,
+    config: Arc<ServerConfig>,
+    tarpit: Arc<Tarpit>,
+    transfer_limit: Arc<TransferLimit>,
+    clock: Arc<dyn Clock>,
+    metrics: Arc<Metrics>,
+    catalog: Arc<MessageCatalog>,
+    middlewares: Arc<Vec<Middleware>>,
+    audit_sink: Arc<dyn AuditSink>,
 }
 
 impl Session {
-    pub fn new(cmd_stream: TcpStream) -> Result<Self> {
-        let cmd_reader = BufReader::new(cmd_stream.try_clone()?);
-        let cmd_writer = BufWriter::new(cmd_stream.try_clone()?);
-        Ok(Session {
-            cmd_reader,
-            cmd_writer,
+    pub fn new(cmd_stream: TcpStream, context: ServerContext) -> Result<Self> {
+        let addrs = (cmd_stream.peer_addr()?, cmd_stream.local_addr()?);
+        cmd_stream.set_read_timeout(Some(context.config.idle_timeout))?;
+        let cmd_stream = SharedStream(Arc::new(cmd_stream));
+        let mut session = Self::from_streams(Box::new(cmd_stream.clone()), Box::new(cmd_stream.clone()), addrs, context);
+        session.cmd_stream = Some(cmd_stream.0);
+        Ok(session)
+    }
+
+    /// Session over arbitrary control streams, e.g. in-memory buffers in tests,
+    ///     so it doesn't need a socket, the control connection appears to be from 127.0.0.1
+    #[cfg(test)]
+    pub fn with_streams<R, W>(reader: R, writer: W, config: Arc<ServerConfig>) -> Self
+    where
+        R: Read + Send + 'static,
+        W: Write + Send + 'static,
+    {
+        let addrs = ("127.0.0.1:50000".parse().unwrap(), "127.0.0.1:21".parse().unwrap());
+        Self::from_streams(Box::new(reader), Box::new(writer), addrs, ServerContext::new(config))
+    }
+
+    /// `addrs` are the (client, server) addresses of the control connection
+    fn from_streams(
+        reader: Box<dyn Read + Send>,
+        writer: Box<dyn Write + Send>,
+        (client_addr, server_addr): (SocketAddr, SocketAddr),
+        context: ServerContext,
+    ) -> Self {
+        let ServerContext {
+            config,
+            tarpit,
+            transfer_limit,
+            clock,
+            metrics,
+            catalog,
+            middlewares,
+            audit_sink,
+        } = context;
+        Session {
+            id: NEXT_SESSION_ID.fetch_add(1, Ordering::Relaxed),
+            cmd_reader: BufReader::new(reader),
+            cmd_writer: BufWriter::new(writer),
+            pipelined: false,
+            rejected_pipelined: false,
+            cmd_stream: None,
+            client_addr,
+            server_addr,
             login_status: LoginStatus::Unloggedin,
             transfer_mode: TransferMode::NotSpecified,
-        })
+            pasv_times: VecDeque::new(),
+            transfer_type: config.default_transfer_type,
+            list_format: ListFormat::Unix,
+            restart_offset: 0,
+            umask: config.umask,
+            idle_timeout: config.idle_timeout,
+            history: VecDeque::with_capacity(HISTORY_LEN),
+            client_name: None,
+            virtual_host: None,
+            transferring: None,
+            transfer_progress: Arc::new(AtomicUsize::new(0)),
+            config,
+            tarpit,
+            transfer_limit,
+            clock,
+            metrics,
+            catalog,
+            middlewares,
+            audit_sink,
+        }
+    }
+
+    /// `resp` rendered with the server's message catalog
+    fn reply<T: ResponseMessage>(&self, resp: T) -> String {
+        resp.render(&self.catalog)
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn clock(&self) -> &dyn Clock {
+        self.clock.as_ref()
+    }
+
+    #[cfg(test)]
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    /// the 220 reply sent when client connects, see `greeting_advertises_version`
+    ///     a greeting set in the message catalog replaces the version banner too
+    pub fn greeting(&self) -> String {
+        let greeting = response::Greeting220::default();
+        let overridden = greeting.catalog_key().is_some_and(|key| self.catalog.contains_key(key));
+        if !self.config.greeting_advertises_version || overridden {
+            return self.reply(greeting);
+        }
+        let name = env!("CARGO_PKG_NAME");
+        let version = env!("CARGO_PKG_VERSION");
+        self.reply(response::Greeting220::new(format!("{name:} {version:} ready ({})", extension_commands().join(", "))))
+    }
+
+    pub fn client_ip(&self) -> IpAddr {
+        self.client_addr.ip()
     }
 
     /// receive one line message and parse it to command
     /// returns err when failed to get message, thus the conn should be closed
     /// returns ok but the inner value may be none if parse failed
+    /// blank lines are skipped if `ignore_blank_lines` is configured
+    /// with `reject_pipelined_commands`, a command that was already buffered when the previous one was read
+    ///     (so it was sent before the previous reply) is rejected with 503 instead of being parsed
+    /// a line that isn't valid UTF-8 is a parse error (500), the connection is kept
     pub fn get_cmd(&mut self) -> Result<Result<Command>> {
-        let line = self.get_msg_not_trimmed()?;
-        let line = line.trim();
-        debug!("Recv message: {line:}");
-        Ok(Command::parse(line))
+        loop {
+            let line = String::from_utf8(self.get_msg_not_trimmed()?);
+            let line = line.as_deref().map(str::trim);
+            debug!("Recv message: {}", line.unwrap_or("<invalid UTF-8>"));
+            if matches!(line, Ok("")) && self.config.ignore_blank_lines {
+                continue;
+            }
+            let sent_early = std::mem::replace(
+                &mut self.pipelined,
+                self.config.reject_pipelined_commands && !self.cmd_reader.buffer().is_empty(),
+            );
+            self.rejected_pipelined = sent_early;
+            if sent_early {
+                debug!("Rejected pipelined command {}", line.unwrap_or("<invalid UTF-8>"));
+                return Ok(Err(response::ResponseError::from(response::WrongCmdSequence503::new(
+                    "Command sent before the previous reply, rejected.",
+                ))
+                .into()));
+            }
+            let Ok(line) = line else {
+                return Ok(Err(response::ResponseError::from(response::SyntaxErr500::new("Command is not valid UTF-8.")).into()));
+            };
+            return Ok(Command::parse(apply_alias(line, &self.config.command_aliases)));
+        }
+    }
+
+    /// the error returned by the last `get_cmd` is a rejected pipelined command rather than one that failed to parse
+    pub fn rejected_pipelined(&self) -> bool {
+        self.rejected_pipelined
     }
 
-    /// receive one line message from client
-    fn get_msg_not_trimmed(&mut self) -> Result<String> {
-        let mut buf = String::new();
-        let len = self.cmd_reader.read_line(&mut buf)?;
+    /// receive one line message from client, with TELNET commands stripped
+    fn get_msg_not_trimmed(&mut self) -> Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        let len = match self.cmd_reader.read_until(b'\n', &mut buf) {
+            Ok(len) => len,
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                self.send_msg_check_crlf(response::ServiceNotAvalible421::new("Timeout."))?;
+                return Err(anyhow!("idle for {:?}, connection closed", self.idle_timeout));
+            }
+            Err(e) => return Err(e.into()),
+        };
         if len == 0 {
             return Err(anyhow!("EOF reached, connection closed"));
         }
-        Ok(buf)
+        Ok(strip_telnet_commands(&buf))
     }
 
     /// send one line message to client
@@ -102,32 +503,33 @@ impl Session {
         if !msg.ends_with("\r\n") {
             msg = format!("{msg:}\r\n");
         }
-        debug!("Send message: {}", msg.trim());
+        let code = msg.get(..3).and_then(|code| code.parse().ok());
+        logging::with_reply_code(code, || debug!("Send message: {}", msg.trim()));
         self.cmd_writer.write_all(msg.as_bytes())?;
         self.cmd_writer.flush()?;
         Ok(())
     }
 
-    fn exec_quit(&mut self, _args: Vec<String>) -> Result<String> {
-        self.send_msg_check_crlf(response::Goodbye221::default().to_string())?;
+    fn exec_quit(&mut self, _cmd: &Command) -> Result<String> {
+        self.send_msg_check_crlf(self.reply(response::Goodbye221::default()))?;
         Err(anyhow!("quit"))
     }
 
-    fn exec_user(&mut self, args: Vec<String>) -> Result<String> {
-        let username = &args[0];
+    fn exec_user(&mut self, cmd: &Command) -> Result<String> {
+        let username = cmd.arg0();
         Ok(match self.login_status {
             LoginStatus::Loggedin(_) => {
                 response::NotLoggedin530::new("Can't change to another user.").to_string()
             }
             LoginStatus::Unloggedin | LoginStatus::Username(_) => {
                 self.login_status = LoginStatus::Username(username.into());
-                response::NeedPassword331::default().to_string()
+                self.reply(response::NeedPassword331::default())
             }
         })
     }
 
-    fn exec_pass(&mut self, args: Vec<String>) -> Result<String> {
-        let passwd = &args[0];
+    fn exec_pass(&mut self, cmd: &Command) -> Result<String> {
+        let passwd = cmd.arg0();
         Ok(match &self.login_status {
             LoginStatus::Unloggedin => {
                 response::WrongCmdSequence503::new("Login with USER first.").to_string()
@@ -136,199 +538,1422 @@ impl Session {
                 response::LoginSuccess230::new("Already logged in.").to_string()
             }
             LoginStatus::Username(username) => {
-                if fake_user_valid(username, passwd) {
-                    self.login_status = LoginStatus::Loggedin(username.into());
-                    response::LoginSuccess230::default().to_string()
+                let username = username.clone();
+                if is_anonymous_user(&username) && !self.allow_anonymous() {
+                    debug!("Anonymous login is disabled, rejected {username:}.");
+                    self.login_failed(&username);
+                    response::NotLoggedin530::new("Anonymous login is disabled.").to_string()
+                } else if fake_user_valid(&username, passwd) {
+                    self.audit_sink.login_attempt(&username, self.client_ip(), true);
+                    self.login_status = LoginStatus::Loggedin(username);
+                    self.login_message()
                 } else {
-                    self.login_status = LoginStatus::Unloggedin;
+                    self.login_failed(&username);
                     response::NotLoggedin530::new("Login incorrect.").to_string()
                 }
             }
         })
     }
 
-    fn exec_pasv(&mut self, _args: Vec<String>) -> Result<String> {
-        check_permission_or_return!(self);
- 
-        // Does nothing when is in pasv mode already
-        if let Some(port) = portpicker::pick_unused_port() {
-            if let Ok(listener) = TcpListener::bind(format!("{LISTENING_HOST:}:{port:}")) {
-                debug!("Entering pasv mode, listening client on {port:}");
-                self.transfer_mode = TransferMode::Pasv(port, listener);
+    fn login_failed(&mut self, username: &str) {
+        self.login_status = LoginStatus::Unloggedin;
+        self.tarpit.record_failure(self.client_ip());
+        self.audit_sink.login_attempt(username, self.client_ip(), false);
+    }
+
+    /// `allow_anonymous` of the virtual host if one is selected, otherwise of the server
+    fn allow_anonymous(&self) -> bool {
+        self.virtual_host.as_ref().map_or(self.config.allow_anonymous, |host| host.allow_anonymous)
+    }
 
-                let (p1, p2) = (port / 256, port % 256);
-                let comma_hostname = hostname_to_comma_hostname(get_local_hostname());
-                return Ok(response::PasvMode227::new(format!("({comma_hostname:},{p1:},{p2:})")).to_string());    
+    /// 230 with file at `login_message_path` as the multi-line part, fallback to default 230 if file is unreadable
+    ///     the virtual host's `login_message_path` is used if it has one
+    fn login_message(&self) -> String {
+        let resp = response::LoginSuccess230::default();
+        let path = self.virtual_host.as_ref().and_then(|host| host.login_message_path.as_ref());
+        let Some(path) = path.or(self.config.login_message_path.as_ref()) else {
+            return self.reply(resp);
+        };
+        match std::fs::read_to_string(path) {
+            Ok(message) => resp.with_lines_in(&self.catalog, &message.lines().collect::<Vec<_>>()),
+            Err(e) => {
+                error!("Failed reading login message {}: {e:}", path.display());
+                self.reply(resp)
             }
         }
-        error!("No avalible port for pasv or cannot establish listener.");
-        Err(anyhow!(response::ServiceNotAvalible421::default().to_string()))
     }
 
-    /// decorate the data_transfer_logic with data conn management logic, so the inner logic don't need to care about it
-    fn data_connection_wrapper<F: Fn(&mut TcpStream) -> Result<()>>(&mut self, data_transfer_logic: F) -> Result<String> {
-        let transfer_mode = std::mem::replace(&mut self.transfer_mode, TransferMode::NotSpecified);
-        match transfer_mode {
-            TransferMode::NotSpecified => Ok(response::NoModeSpecified425::default().to_string()),
-            TransferMode::Pasv(_, listener) => {
-                if let Ok((mut stream, _)) = listener.accept() {
-                    self.send_msg_check_crlf(response::DataTransferStarts150::default())?;
-                    data_transfer_logic(&mut stream)?;
-                    return Ok(response::DataTransferFinished226::default().to_string());
-                }
-                Err(anyhow!(response::ServiceNotAvalible421::default().to_string()))
-            },
+    /// the address sent in PASV reply, `pasv_address` if configured,
+    ///     otherwise the server's address of the control connection
+    fn pasv_address(&self) -> String {
+        if let Some(address) = &self.config.pasv_address {
+            return address.clone();
+        }
+        match self.server_addr.ip() {
+            IpAddr::V6(ip) => ip.to_ipv4_mapped().map_or(ip.to_string(), |ip| ip.to_string()),
+            ip => ip.to_string(),
         }
     }
 
-    fn exec_list(&mut self, _args: Vec<String>) -> Result<String> {
-        check_permission_or_return!(self);
-        self.data_connection_wrapper(|stream| -> Result<()> {
-            stream.write_all(".\r\n..\r\nthis\r\noutput\r\nis\r\nfake\r\n".as_bytes())?;
-            stream.flush()?;
-            Ok(())
-        })
-    }
+    fn exec_pasv(&mut self, _cmd: &Command) -> Result<String> {
+        let pasv_address = self.pasv_address();
+        let Some(comma_address) = address_to_comma_address(&pasv_address) else {
+            error!("Pasv address {pasv_address:} is not an ipv4 address.");
+            return Ok(response::NetworkProtocolNotSupported522::new("PASV needs an IPv4 server address.").to_string());
+        };
 
-    fn exec_fakecmdwithtwoarg(&mut self, _args: Vec<String>) -> Result<String> {
-        unreachable!()
+        match self.enter_pasv_mode() {
+            Ok(advertised_port) => {
+                let (p1, p2) = encode_pasv_port(advertised_port);
+                Ok(response::PasvMode227::new(format!("({comma_address:},{p1:},{p2:})")).to_string())
+            }
+            Err(resp) => Ok(resp),
+        }
     }
 
-    fn exec_port(&mut self, _args: Vec<String>) -> Result<String> {
-        Ok(response::NotImplementedCommand502::default().to_string())
+    /// SPSV (single port passive) is PASV replying only the port, the client connects to the control connection's host,
+    ///     so it also works when the server has no IPv4 address to report
+    fn exec_spsv(&mut self, _cmd: &Command) -> Result<String> {
+        match self.enter_pasv_mode() {
+            Ok(advertised_port) => Ok(response::PasvMode227::new(format!("({advertised_port:})")).to_string()),
+            Err(resp) => Ok(resp),
+        }
     }
-}
-
 
+    /// bind a passive listener for the next data command
+    /// Returns the port to send to client, or Err(reply) if no listener can be bound
+    fn enter_pasv_mode(&mut self) -> std::result::Result<u16, String> {
+        if !self.pasv_rate_allows() {
+            error!("Too many passive listeners within {:?}.", self.config.pasv_rate_window);
+            return Err(response::ServiceNotAvalible421::new("Too many PASV requests, try again later.").to_string());
+        }
 
-macro_rules! register_command_handlers {
-    ($($cmd: ident), *) => {
-        impl crate::Session {
-            /// Returns Ok(Message) then Message will be send to client
-            /// Returns Err(e) then conn will be closed
-            pub fn exec_cmd(&mut self, cmd: Command) -> anyhow::Result<String> {
-                match cmd {
-                    $(
-                        // `paste` will concat function names like exec_quit, exec_user and so on
-                        //      so that I don't need to write all these match arms by myself
-                        Command::$cmd(arg) => paste!{ self.[<exec_ $cmd:lower>](arg) },
-                    )*
-                }
+        // Does nothing when is in pasv mode already
+        if let Some(port) = portpicker::pick_unused_port() {
+            let Some(advertised_port) = self.config.pasv_advertised_port(port) else {
+                error!("Pasv port {port:} is out of range after applying offset {}.", self.config.pasv_port_offset);
+                return Err(response::ServiceNotAvalible421::new("No passive port available, try again.").to_string());
+            };
+            let listener = match self.config.pasv_bind_address {
+                Some(ip) => TcpListener::bind((ip, port)),
+                None => TcpListener::bind(format!("{LISTENING_HOST:}:{port:}")),
+            };
+            if let Ok(listener) = listener {
+                debug!("Entering pasv mode, listening client on {port:}, advertised as {advertised_port:}");
+                self.transfer_mode = TransferMode::Pasv(port, self.metrics.track_pasv_listener(listener));
+                self.pasv_times.push_back(Instant::now());
+                return Ok(advertised_port);
             }
         }
+        // a transient port shortage, the client can retry PASV so the session is kept
+        error!("No avalible port for pasv or cannot establish listener.");
+        Err(response::ServiceNotAvalible421::new("No passive port available, try again.").to_string())
+    }
 
+    /// whether another passive listener can be bound without exceeding `max_pasv_per_window`
+    fn pasv_rate_allows(&mut self) -> bool {
+        let window = self.config.pasv_rate_window;
+        while self.pasv_times.front().is_some_and(|bound_at| bound_at.elapsed() >= window) {
+            self.pasv_times.pop_front();
+        }
+        self.config.max_pasv_per_window == 0 || self.pasv_times.len() < self.config.max_pasv_per_window
     }
-}
 
-register_command_handlers!(Quit, User, Pass, FakeCmdWithTwoArg, Pasv, Port, List);
+    /// connect to the client's data port, from `active_data_source_port` if it can be bound
+    fn connect_active(&self, addr: SocketAddrV4, deadline: Option<Instant>) -> io::Result<TcpStream> {
+        let timeout = time_left(deadline).map(|timeout| timeout.max(Duration::from_millis(1)));
+        if let Some(source_port) = self.config.active_data_source_port {
+            match socket_opts::bind_tcp_socket(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, source_port)) {
+                Ok(socket) => return socket_opts::connect_socket(socket, addr, timeout),
+                Err(e) => warn!("Failed binding data source port {source_port:}, connecting from an ephemeral port: {e:}"),
+            }
+        }
+        match timeout {
+            Some(timeout) => TcpStream::connect_timeout(&SocketAddr::V4(addr), timeout),
+            None => TcpStream::connect(addr),
+        }
+    }
 
-#[cfg(test)]
-mod session_test {
-    use super::*;
-    use crate::{integration_test::utils::*, response, integration_test::{USERNAME, PASSWORD}};
-    mod setup {
-        use super::*;
-        use crate::integration_test::TestClient;
-        use std::{
-            net::TcpListener,
-            sync::{Mutex, Once},
-            thread, vec,
+    /// Answer a STAT the client sent during a transfer, see `stat_during_transfer_after`.
+    ///     Only what's already received is looked at, the control connection is read without blocking.
+    ///     Any other command is left unread and served after the transfer, in order.
+    ///     STAT goes through the same checks as any command, so it may be rejected, e.g. when it's disabled
+    fn answer_stat_during_transfer(&mut self) {
+        let Some(cmd_stream) = self.cmd_stream.clone() else {
+            return;
         };
+        let buffered = match self.cmd_reader.buffer() {
+            [] => {
+                if let Err(e) = cmd_stream.set_nonblocking(true) {
+                    debug!("Failed polling control connection: {e:}");
+                    return;
+                }
+                let received = self.cmd_reader.fill_buf().map(|buf| buf.to_vec());
+                let _ = cmd_stream.set_nonblocking(false);
+                match received {
+                    Ok(received) => received,
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => return,
+                    Err(e) => {
+                        debug!("Failed polling control connection: {e:}");
+                        return;
+                    }
+                }
+            }
+            buffered => buffered.to_vec(),
+        };
+        let Some(line_len) = buffered.iter().position(|&b| b == b'\n').map(|pos| pos + 1) else {
+            return;
+        };
+        let line = String::from_utf8_lossy(&strip_telnet_commands(&buffered[..line_len])).trim().to_string();
+        let Ok(cmd @ Command::Stat(_)) = Command::parse(&line) else {
+            return;
+        };
+        self.cmd_reader.consume(line_len);
+        debug!("Recv message during transfer: {line:}");
+        let resp = self.dispatch_during_transfer(cmd).and_then(|resp| self.send_msg_check_crlf(resp));
+        if let Err(e) = resp {
+            debug!("Failed answering STAT during transfer: {e:}");
+        }
+    }
 
-        static INIT: Once = Once::new();
-        static mut LISTENER: Option<Mutex<TcpListener>> = None;
-
+    /// decorate the data_transfer_logic with data conn management logic, so the inner logic don't need to care about it
+    /// data_transfer_logic returns what it transferred, `filename` and `direction` describe the transfer in access log
+    /// the whole command is aborted if it takes longer than `command_timeout`
+    /// a data connection dropped by client (e.g. a cancelled listing) is answered with 426 and keeps the session alive
+    /// Returns the reply, with the transfer result if the transfer completed
+    fn data_connection_wrapper<F: FnMut(&mut DataStream) -> Result<TransferResult>>(&mut self, filename: &str, direction: Direction, mut data_transfer_logic: F) -> Result<(String, Option<TransferResult>)> {
+        let deadline = self.config.command_timeout.map(|timeout| Instant::now() + timeout);
+        let transfer_mode = std::mem::replace(&mut self.transfer_mode, TransferMode::NotSpecified);
+        if matches!(transfer_mode, TransferMode::NotSpecified) {
+            return Ok((self.reply(response::NoModeSpecified425::default()), None));
+        }
+        // held until the transfer is done
+        let Some(_slot) = self.transfer_limit.acquire(&self.transfer_owner()) else {
+            debug!("Too many transfers of {}, rejected transfer of {filename:}", self.transfer_owner());
+            return Ok((response::CannotOpenDataConnection425::new("Too many data connections.").to_string(), None));
+        };
+        let mut stream = match transfer_mode {
+            TransferMode::NotSpecified => unreachable!(),
+            TransferMode::Pasv(port, listener) => {
+                debug!("Waiting for data connection on {port:}");
+                let (stream, data_addr) = match accept_before(&listener, deadline) {
+                    Ok(accepted) => accepted,
+                    Err(e) if e.kind() == ErrorKind::TimedOut => {
+                        error!("No data connection on {port:} before deadline");
+                        return Ok((response::CannotOpenDataConnection425::new("Can't open data connection: timed out.").to_string(), None));
+                    }
+                    Err(_) => return Err(anyhow!(self.reply(response::ServiceNotAvalible421::default()))),
+                };
+                if self.config.require_matching_data_ip && data_addr.ip() != self.client_addr.ip() {
+                    error!("Rejected data connection from {data_addr:}, control connection is from {}", self.client_addr);
+                    return Ok((response::CannotOpenDataConnection425::new("Data connection must come from the control connection's address.").to_string(), None));
+                }
+                stream
+            },
+            TransferMode::Port(addr) => {
+                debug!("Connecting to client's data port {addr:}");
+                match self.connect_active(addr, deadline) {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        error!("Failed connecting to {addr:}: {e:}");
+                        let reason = match e.kind() {
+                            ErrorKind::ConnectionRefused => "connection refused",
+                            ErrorKind::TimedOut | ErrorKind::WouldBlock => "timed out",
+                            _ => return Ok((self.reply(response::CannotOpenDataConnection425::default()), None)),
+                        };
+                        return Ok((response::CannotOpenDataConnection425::new(format!("Can't open data connection: {reason:}.")).to_string(), None));
+                    }
+                }
+            },
+        };
 
-        // setup a listener and move it into LISTENER
-        fn setup_listener() {
-            INIT.call_once(|| unsafe {
-                let listener = TcpListener::bind("0.0.0.0:12345").unwrap();
-                LISTENER = Some(Mutex::new(listener))
-            })
+        self.apply_buffer_sizes(&stream);
+        if let Some(timeout) = time_left(deadline) {
+            // zero timeout is rejected by socket options, the transfer will just time out on first read/write
+            let timeout = timeout.max(Duration::from_millis(1));
+            stream.set_read_timeout(Some(timeout))?;
+            stream.set_write_timeout(Some(timeout))?;
         }
 
-        fn setup_client() -> TestClient {
-            let client = TcpStream::connect("127.0.0.1:12345").unwrap();
-            let cmd_reader = BufReader::new(client.try_clone().unwrap());
-            let cmd_writer = BufWriter::new(client.try_clone().unwrap());
-            TestClient {
-                cmd_reader,
-                cmd_writer,
+        self.send_msg_check_crlf(self.reply(response::DataTransferStarts150::default()))?;
+        self.transfer_progress.store(0, Ordering::Relaxed);
+        self.transferring = Some(filename.to_string());
+        let progress = self.transfer_progress.clone();
+        let result = match self.config.stat_during_transfer_after {
+            None => data_transfer_logic(&mut DataStream::new(&mut stream, progress)),
+            Some(after) => {
+                let started = Instant::now();
+                let mut last_poll = None::<Instant>;
+                let mut poll = || {
+                    if started.elapsed() >= after && last_poll.is_none_or(|last| last.elapsed() >= CONTROL_POLL_INTERVAL) {
+                        last_poll = Some(Instant::now());
+                        self.answer_stat_during_transfer();
+                    }
+                };
+                data_transfer_logic(&mut DataStream::new(&mut stream, progress).with_poll(&mut poll))
+            }
+        };
+        // the client must see all data and the end of the data connection before 226, some clients stop reading
+        //     data once they get 226 and would truncate what's still in flight
+        if result.is_ok() {
+            if let Err(e) = stream.flush().and_then(|_| stream.shutdown(Shutdown::Write)) {
+                debug!("Failed closing data connection of {filename:}: {e:}");
             }
         }
+        drop(stream);
+        self.transferring = None;
+        self.log_transfer(filename, direction, result.as_ref().map_or(0, |transfer| transfer.bytes), result.is_ok());
+        match result {
+            Ok(transfer) => Ok((self.reply(response::DataTransferFinished226::default()), Some(transfer))),
+            Err(e) if is_timeout(&e) => {
+                error!("Transfer of {filename:} timed out");
+                Ok((response::TransferAborted426::new("Transfer timed out.").to_string(), None))
+            }
+            // only the data connection is gone, the session goes on
+            Err(e) if is_connection_dropped(&e) => {
+                error!("Data connection of {filename:} dropped: {e:}");
+                Ok((self.reply(response::TransferAborted426::default()), None))
+            }
+            Err(e) => Err(e),
+        }
+    }
 
-        pub fn setup_client_and_session_unlogged() -> (TestClient, Session) {
-            setup_listener();
-
-            let accept_thread = thread::spawn(move || unsafe {
-                let listener_guard = LISTENER.as_ref().unwrap().lock().unwrap();
-                let conn_thread = thread::spawn(setup_client);
-                let (stream, _) = listener_guard.accept().unwrap();
-                (conn_thread.join().unwrap(), Session::new(stream).unwrap())
-            });
-            accept_thread.join().unwrap()
+    /// whose transfers are counted against `max_transfers_per_user`, the username,
+    ///     or the ip for anonymous users so that they don't share one limit
+    fn transfer_owner(&self) -> String {
+        match &self.login_status {
+            LoginStatus::Loggedin(username) if !is_anonymous_user(username) => username.clone(),
+            _ => self.client_ip().to_string(),
         }
-        /// create a TestClient and a Session, the client is connected to the session
-        pub fn setup_client_and_session_and_login() -> (TestClient, Session) {
-            let (client, mut session) = setup_client_and_session_unlogged();
-            session.exec_user(vec![USERNAME.to_string()]).unwrap();
-            session.exec_pass(vec![PASSWORD.to_string()]).unwrap();   
-            (client, session)
+    }
+
+    /// set configured buffer sizes on data connection, failing to set them doesn't fail the transfer
+    fn apply_buffer_sizes(&self, stream: &TcpStream) {
+        let sizes = [
+            (Buffer::Receive, self.config.data_recv_buffer_size),
+            (Buffer::Send, self.config.data_send_buffer_size),
+        ];
+        for (buffer, size) in sizes {
+            if let Some(size) = size {
+                if let Err(e) = socket_opts::set_buffer_size(stream, buffer, size) {
+                    error!("Failed setting {buffer:?} buffer of data connection to {size:}: {e:}");
+                    continue;
+                }
+                // the kernel rounds the size and caps it (e.g. by net.core.rmem_max on linux) without an error
+                match socket_opts::buffer_size(stream, buffer) {
+                    Ok(actual) => debug!("{buffer:?} buffer of data connection is {actual:} bytes, {size:} requested"),
+                    Err(e) => debug!("Failed reading {buffer:?} buffer size of data connection: {e:}"),
+                }
+            }
         }
     }
 
-    #[test]
-    fn test_create_session() {
-        let (_, _) = setup::setup_client_and_session_and_login();
+    /// append transfer to access log if it's configured, failing to log doesn't fail the transfer
+    fn log_transfer(&self, filename: &str, direction: Direction, bytes: usize, complete: bool) {
+        if let Some(path) = &self.config.access_log_path {
+            let record = TransferRecord {
+                client_ip: self.client_addr.ip(),
+                bytes,
+                filename,
+                direction,
+                complete,
+            };
+            if let Err(e) = access_log::append(path, self.clock.now(), &record) {
+                error!("Failed writing access log {}: {e:}", path.display());
+            }
+        }
     }
 
-    #[test]
-    fn test_send_msg() {
-        let (mut client, mut session) = setup::setup_client_and_session_and_login();
+    fn exec_list(&mut self, cmd: &Command) -> Result<String> {
+        let path = list_path(cmd.arg0()).to_string();
+        let entries = visible_entries(&[".", "..", "this", "output", "is", "fake"], self.config.hide_dotfiles);
+        let entries = match self.list_format {
+            ListFormat::Unix => entries.iter().map(|entry| entry.to_string()).collect::<Vec<_>>(),
+            // the fake entries have no metadata, `.` and `..` are shown as directories and the others as empty files
+            ListFormat::Dos => {
+                let now = self.clock.now();
+                entries.iter().map(|entry| format_dos_entry(entry, matches!(*entry, "." | ".."), 0, now)).collect()
+            }
+        };
+        let entries = entries.iter().map(String::as_str).collect::<Vec<_>>();
+        // listings follow TYPE, whatever line endings they are made with
+        let listing = normalize_line_endings(&format_listing(&entries, self.config.max_list_entries), self.transfer_type);
+        let (resp, _) = self.data_connection_wrapper(&path, Direction::Outgoing, |stream| {
+            stream.write_all(listing.as_bytes())?;
+            stream.flush()?;
+            Ok(TransferResult { bytes: listing.len() })
+        })?;
+        Ok(resp)
+    }
 
-        let msg = "message";
-        session.send_msg_check_crlf(msg).unwrap();
-        assert_string_trim_eq(client.get_msg_trimed().unwrap(), msg);
+    /// SITE's argument is a subcommand followed by its own argument, e.g. `SITE UMASK 027`
+    fn exec_site(&mut self, cmd: &Command) -> Result<String> {
+        let (subcommand, arg) = cmd.arg0().split_once(' ').unwrap_or((cmd.arg0(), ""));
+        let handler = SITE_COMMANDS
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(subcommand))
+            .map(|(_, handler)| *handler);
+        match handler {
+            Some(handler) => handler(self, arg.trim()),
+            None => Ok(response::SyntaxErr500::new("Unknown SITE command.").to_string()),
+        }
     }
 
-    #[test]
-    fn test_send_resp() {
-        let (mut client, mut session) = setup::setup_client_and_session_and_login();
+    /// whether SITE subcommand `name` can be used, i.e. SITE isn't in `disabled_commands`
+    ///     and EXEC is only usable with `site_exec_enabled`
+    fn site_command_enabled(&self, name: &str) -> bool {
+        let site_disabled = self.config.disabled_commands.iter().any(|verb| verb.eq_ignore_ascii_case("SITE"));
+        !site_disabled && (name != "EXEC" || self.config.site_exec_enabled)
+    }
 
-        session
-            .send_msg_check_crlf(response::UnknownRespWithoutDefaultMessage999::new(
-                "message",
-            ))
-            .unwrap();
-        assert_string_trim_eq(client.get_msg_trimed().unwrap(), "999 message");
+    /// `SITE EXEC /path/to/program [args]` runs a whitelisted program and replies its output, see `site_exec_enabled`.
+    ///     Running programs for clients is dangerous, so both the flag and an exact match in `site_exec_allowed` are
+    ///     required, arguments are never interpreted by a shell and each run is reported to the audit sink.
+    ///     The program runs with an empty environment, is killed after `command_timeout` (`SITE_EXEC_TIMEOUT` if unset)
+    ///     and only `SITE_EXEC_MAX_OUTPUT` bytes of its output are kept, see `site_exec::run`.
+    ///     Replies 200 with stdout if it exits successfully, otherwise 550 with stdout and stderr
+    fn exec_site_exec(&mut self, arg: &str) -> Result<String> {
+        if !self.config.site_exec_enabled {
+            return Ok(response::NotImplementedCommand502::new("SITE EXEC is disabled.").to_string());
+        }
+        let argv = arg.split_ascii_whitespace().collect::<Vec<_>>();
+        // relative programs are rejected by `ServerConfig::validate`, they never match here either
+        let allowed = argv.first().is_some_and(|program| Path::new(program).is_absolute())
+            && self.config.site_exec_allowed.iter().any(|allowed| *allowed == argv);
+        if !allowed {
+            debug!("Rejected SITE EXEC {arg:}");
+            return Ok(response::PermissionDenied550::new("Command not allowed.").to_string());
+        }
+        let username = match &self.login_status {
+            LoginStatus::Loggedin(username) => username.as_str(),
+            _ => "",
+        };
+        self.audit_sink.privileged_operation(username, self.client_ip(), "SITE EXEC", arg);
+        let timeout = self.config.command_timeout.unwrap_or(SITE_EXEC_TIMEOUT);
+        let output = match site_exec::run(&argv, timeout, SITE_EXEC_MAX_OUTPUT) {
+            Ok(output) => output,
+            Err(e) => {
+                error!("Failed running {arg:}: {e:}");
+                return Ok(response::PermissionDenied550::new("Command failed to run.").to_string());
+            }
+        };
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        if !stderr.is_empty() {
+            warn!("SITE EXEC {arg:} wrote to stderr: {}", stderr.trim_end());
+        }
+        let mut lines = stdout.lines().collect::<Vec<_>>();
+        if output.truncated {
+            lines.push("... output truncated");
+        }
+        let Some(status) = output.status.filter(|status| status.success()) else {
+            lines.extend(stderr.lines());
+            let reason = match output.status.map(|status| status.code()) {
+                None => format!("Killed after {timeout:?}"),
+                Some(None) => "Killed by signal".to_string(),
+                Some(Some(code)) => format!("Exit status {code:}"),
+            };
+            return Ok(response::PermissionDenied550::new(reason).with_lines(&lines));
+        };
+        Ok(response::CommandOkay200::new(format!("Exit status {}", status.code().unwrap_or(0))).with_lines(&lines))
     }
 
-    #[test]
-    fn test_get_cmd() {
-        let (mut client, mut session) = setup::setup_client_and_session_and_login();
+    /// `SITE LISTFMT` reports the LIST format of the session, `SITE LISTFMT unix|dos` changes it
+    fn exec_site_listfmt(&mut self, arg: &str) -> Result<String> {
+        let list_format = match arg.to_ascii_lowercase().as_str() {
+            "" => {
+                let name = if self.list_format == ListFormat::Unix { "unix" } else { "dos" };
+                return Ok(response::CommandOkay200::new(format!("LIST format is {name:}")).to_string());
+            }
+            "unix" => ListFormat::Unix,
+            "dos" => ListFormat::Dos,
+            _ => return Ok(response::InvalidParameter501::new("LIST format must be unix or dos.").to_string()),
+        };
+        self.list_format = list_format;
+        Ok(response::CommandOkay200::new(format!("LIST format set to {}", arg.to_ascii_lowercase())).to_string())
+    }
 
-        client.send_msg_add_crlf("QUIT arg").unwrap();
-        let cmd = session.get_cmd().unwrap();
-        assert!(cmd.is_ok());
-        assert!(matches!(cmd.unwrap(), Command::Quit(_)));
+    /// `SITE UMASK` reports the session's umask, `SITE UMASK 027` changes it
+    fn exec_site_umask(&mut self, arg: &str) -> Result<String> {
+        if arg.is_empty() {
+            return Ok(response::CommandOkay200::new(format!("Your current UMASK is {:03o}", self.umask)).to_string());
+        }
+        match u32::from_str_radix(arg, 8) {
+            Ok(umask) if umask <= 0o777 => {
+                self.umask = umask;
+                Ok(response::CommandOkay200::new(format!("UMASK set to {umask:03o}")).to_string())
+            }
+            _ => Ok(response::InvalidParameter501::new("Bad UMASK value.").to_string()),
+        }
     }
 
-    #[test]
-    fn test_exec_quit() {
-        let (_, mut session) = setup::setup_client_and_session_and_login();
+    /// `SITE IDLE` reports the session's idle timeout, `SITE IDLE 600` changes it, clamped to `max_idle_timeout`
+    fn exec_site_idle(&mut self, arg: &str) -> Result<String> {
+        if arg.is_empty() {
+            return Ok(response::CommandOkay200::new(format!("Current idle timeout is {}", self.idle_timeout.as_secs())).to_string());
+        }
+        match arg.parse::<u64>() {
+            Ok(secs) if secs > 0 => {
+                let idle_timeout = Duration::from_secs(secs).min(self.config.max_idle_timeout);
+                if let Some(cmd_stream) = &self.cmd_stream {
+                    cmd_stream.set_read_timeout(Some(idle_timeout))?;
+                }
+                self.idle_timeout = idle_timeout;
+                Ok(response::CommandOkay200::new(format!("Idle timeout set to {}", idle_timeout.as_secs())).to_string())
+            }
+            _ => Ok(response::InvalidParameter501::new("Bad IDLE value.").to_string()),
+        }
+    }
 
-        // Quit will return an Err, thus the infinite loop in serve will break and Session will be dropped
-        //      thus the stream in Session will be automaticly closed
-        assert!(session.exec_cmd(Command::Quit(vec![])).is_err());
+    /// lists extensions per RFC 2389, each feature line starts with a space
+    /// every registered SITE subcommand that's usable with this config is listed as `SITE <subcommand>`
+    fn exec_feat(&mut self, _cmd: &Command) -> Result<String> {
+        let features = SITE_COMMANDS
+            .iter()
+            .filter(|(name, _)| self.site_command_enabled(name))
+            .map(|(name, _)| format!(" SITE {name:}\r\n"))
+            .collect::<String>();
+        Ok(format!("211-Features:\r\n{features:}{}", self.reply(response::SystemStatus211::default())))
     }
 
-    mod test_loggin {
-        use super::*;
+    /// status of the connection, with the recent commands of this session
+    fn exec_stat(&mut self, _cmd: &Command) -> Result<String> {
+        let mut lines = vec![format!("Connected to {}", self.client_addr)];
+        lines.push(match &self.login_status {
+            LoginStatus::Loggedin(username) => format!("Logged in as {username:}"),
+            _ => "Not logged in".to_string(),
+        });
+        lines.push(match self.transfer_type {
+            TransferType::Ascii => "TYPE: ASCII, FORM: Nonprint".to_string(),
+            TransferType::Binary => "TYPE: BINARY".to_string(),
+        });
+        if let Some(filename) = &self.transferring {
+            lines.push(format!("Transferring {filename:}: {} bytes so far", self.transfer_progress.load(Ordering::Relaxed)));
+        }
+        if let Some(client_name) = &self.client_name {
+            lines.push(format!("Client is {client_name:}"));
+        }
+        lines.push("Recent commands:".to_string());
+        lines.extend(self.history.iter().map(|entry| format!("    {entry:}")));
+        Ok(response::SystemStatus211::default().with_lines_in(&self.catalog, &lines))
+    }
 
+    /// REIN resets the session to the state right after connecting, like RFC 959 requires:
+    ///     login, virtual host, pending data connection and every negotiated parameter (TYPE, LIST format, umask, idle timeout)
+    ///     go back to the server defaults. The control connection and command history are kept
+    fn exec_rein(&mut self, _cmd: &Command) -> Result<String> {
+        if let Some(cmd_stream) = &self.cmd_stream {
+            cmd_stream.set_read_timeout(Some(self.config.idle_timeout))?;
+        }
+        self.login_status = LoginStatus::Unloggedin;
+        self.virtual_host = None;
+        self.transfer_mode = TransferMode::NotSpecified;
+        self.transfer_type = self.config.default_transfer_type;
+        self.list_format = ListFormat::Unix;
+        self.umask = self.config.umask;
+        self.idle_timeout = self.config.idle_timeout;
+        debug!("Session reinitialized");
+        Ok(self.greeting())
+    }
 
-        #[test]
+    /// `HOST <name>` selects a virtual host per RFC 7151, only before USER
+    fn exec_host(&mut self, cmd: &Command) -> Result<String> {
+        if self.login_status != LoginStatus::Unloggedin {
+            return Ok(response::WrongCmdSequence503::new("HOST must be sent before USER.").to_string());
+        }
+        let Some(host) = self.config.virtual_hosts.get(&cmd.arg0().to_ascii_lowercase()) else {
+            debug!("Unknown virtual host {}", cmd.arg0());
+            return Ok(response::ParameterNotImplemented504::new("Unknown host.").to_string());
+        };
+        let resp = match &host.banner {
+            Some(banner) => response::Greeting220::new(banner).to_string(),
+            None => self.greeting(),
+        };
+        self.virtual_host = Some(host.clone());
+        Ok(resp)
+    }
+
+    /// `TYPE A` for ASCII or `TYPE I` for binary (image)
+    /// ASCII only has the default Non-print form, `TYPE A N`, Telnet (`T`) and ASA (`C`) carriage control aren't supported
+    fn exec_type(&mut self, cmd: &Command) -> Result<String> {
+        let arg = cmd.arg0().to_ascii_uppercase();
+        let transfer_type = match arg.split_ascii_whitespace().collect::<Vec<_>>()[..] {
+            ["A"] | ["A", "N"] => TransferType::Ascii,
+            ["A", "T" | "C"] => return Ok(response::ParameterNotImplemented504::new("Unsupported ASCII form.").to_string()),
+            ["I"] => TransferType::Binary,
+            _ => return Ok(response::ParameterNotImplemented504::new("Unsupported TYPE.").to_string()),
+        };
+        self.transfer_type = transfer_type;
+        Ok(response::CommandOkay200::new(format!("Switching to {} mode.", transfer_type.name())).to_string())
+    }
+
+    /// CLNT only records who the client is, for logging
+    fn exec_clnt(&mut self, cmd: &Command) -> Result<String> {
+        debug!("Client {} identified as {}", self.client_addr, cmd.arg0());
+        self.client_name = Some(cmd.arg0().to_string());
+        Ok(response::CommandOkay200::new("Noted.").to_string())
+    }
+
+    /// `REST <offset>` sets where the next transfer starts, `REST 0` resets it, so does a REST that's rejected
+    fn exec_rest(&mut self, cmd: &Command) -> Result<String> {
+        let Ok(offset) = cmd.arg0().parse::<u64>() else {
+            self.restart_offset = 0;
+            return Ok(response::InvalidParameter501::new("REST requires a non-negative offset.").to_string());
+        };
+        self.restart_offset = offset;
+        Ok(response::PendingFurtherInfo350::new(format!("Restarting at {offset:}. Send the transfer command next.")).to_string())
+    }
+
+    fn exec_noop(&mut self, _cmd: &Command) -> Result<String> {
+        Ok(response::CommandOkay200::new("NOOP ok.").to_string())
+    }
+
+    fn exec_fakecmdwithtwoarg(&mut self, _cmd: &Command) -> Result<String> {
+        unreachable!()
+    }
+
+    fn exec_port(&mut self, cmd: &Command) -> Result<String> {
+        let Some(addr) = parse_port_argument(cmd.arg0()) else {
+            return Ok(response::InvalidParameter501::new("Illegal PORT command.").to_string());
+        };
+        if !self.config.allow_foreign_port_address && IpAddr::V4(*addr.ip()) != self.client_addr.ip() {
+            error!("Rejected PORT to {addr:}, control connection is from {}", self.client_addr);
+            return Ok(response::InvalidParameter501::new("PORT address must match control connection.").to_string());
+        }
+        debug!("Entering port mode, client's data port is {addr:}");
+        self.transfer_mode = TransferMode::Port(addr);
+        Ok(response::CommandOkay200::new("PORT command successful.").to_string())
+    }
+}
+
+
+
+/// Describes how a command is dispatched
+struct CommandSpec {
+    /// the command can only be used after login, i.e. it's not in `PRE_LOGIN_COMMANDS`
+    login_required: bool,
+    /// the command transfers data through the connection set up by PASV/PORT
+    data_connection: bool,
+    /// the command needs at least this many arguments, a command built with fewer gets 501 before its handler runs
+    min_args: usize,
+    handler: fn(&mut Session, &Command) -> Result<String>,
+}
+
+macro_rules! register_command_handlers {
+    ($($cmd: ident ($data_connection: literal)), *) => {
+        impl Session {
+            /// Returns the metadata and handler of `cmd`
+            fn command_spec(cmd: &Command) -> CommandSpec {
+                match cmd {
+                    $(
+                        // `paste` will concat function names like exec_quit, exec_user and so on
+                        //      so that I don't need to write all these match arms by myself
+                        Command::$cmd(_) => CommandSpec {
+                            login_required: !PRE_LOGIN_COMMANDS.iter().any(|verb| verb.eq_ignore_ascii_case(cmd.name())),
+                            data_connection: $data_connection,
+                            min_args: cmd.min_args(),
+                            handler: paste!{ Session::[<exec_ $cmd:lower>] },
+                        },
+                    )*
+                }
+            }
+        }
+    }
+}
+
+/// The only commands allowed before login, every other command is rejected with 530 before its handler runs,
+///     so a new command can't leak anything to unauthenticated clients by mistake.
+///     Some of them (HELP, AUTH, SYST) aren't implemented yet
+const PRE_LOGIN_COMMANDS: &[&str] = &["USER", "PASS", "QUIT", "FEAT", "HELP", "NOOP", "AUTH", "HOST", "SYST", "CLNT", "REIN"];
+
+/// SITE subcommands and their handlers, the handler receives the argument after subcommand
+type SiteHandler = fn(&mut Session, &str) -> Result<String>;
+const SITE_COMMANDS: &[(&str, SiteHandler)] = &[
+    ("UMASK", Session::exec_site_umask),
+    ("IDLE", Session::exec_site_idle),
+    ("LISTFMT", Session::exec_site_listfmt),
+    ("EXEC", Session::exec_site_exec),
+];
+
+//                         (data connection)
+register_command_handlers!(
+    Quit(false),
+    User(false),
+    Pass(false),
+    FakeCmdWithTwoArg(false),
+    Pasv(false),
+    Spsv(false),
+    Port(false),
+    List(true),
+    Site(false),
+    Feat(false),
+    Noop(false),
+    Stat(false),
+    Clnt(false),
+    Type(false),
+    Host(false),
+    Rein(false),
+    Rest(false)
+);
+
+impl Session {
+    /// Returns Ok(Message) then Message will be send to client
+    /// Returns Err(e) then conn will be closed
+    /// the command is added to history after it's executed, so STAT doesn't report itself
+    /// an offset set by REST only applies to the command right after it, it's cleared once that command has run
+    pub fn exec_cmd(&mut self, cmd: Command) -> Result<String> {
+        let entry = history_entry(&cmd);
+        let is_rest = matches!(cmd, Command::Rest(_));
+        logging::set_command(Some(cmd.name().to_ascii_uppercase()));
+        let resp = self.dispatch(cmd);
+        logging::set_command(None);
+        if !is_rest {
+            self.restart_offset = 0;
+        }
+        if self.history.len() == HISTORY_LEN {
+            self.history.pop_front();
+        }
+        self.history.push_back(entry);
+        resp
+    }
+
+    /// A data connection set up by PASV/PORT is only kept for the next data command:
+    ///     any other command except PASV/SPSV/PORT/TYPE/REST (which only set transfer parameters) abandons it,
+    ///     so the passive listener is closed instead of lingering until the session ends.
+    ///     It's only abandoned right before the handler runs, a rejected command leaves it in place
+    /// `disabled_commands` are rejected first, then commands not allowed before login get 530,
+    ///     so middlewares only see commands the user may run. Commands with fewer than `min_args` arguments
+    ///     get 501 right before their handler
+    fn dispatch(&mut self, cmd: Command) -> Result<String> {
+        let spec = Self::command_spec(&cmd);
+        if let Some(resp) = self.reject(&cmd, &spec) {
+            return Ok(resp);
+        }
+        if !spec.data_connection && !keeps_data_connection(&cmd) {
+            self.abandon_data_connection();
+        }
+        (spec.handler)(self, &cmd)
+    }
+
+    /// `dispatch` of a command received while a transfer is running, e.g. STAT,
+    ///     the data connection of the transfer isn't abandoned
+    fn dispatch_during_transfer(&mut self, cmd: Command) -> Result<String> {
+        let spec = Self::command_spec(&cmd);
+        if let Some(resp) = self.reject(&cmd, &spec) {
+            return Ok(resp);
+        }
+        (spec.handler)(self, &cmd)
+    }
+
+    /// The reply of a command rejected before its handler runs, see `dispatch`
+    fn reject(&mut self, cmd: &Command, spec: &CommandSpec) -> Option<String> {
+        if self.config.disabled_commands.iter().any(|verb| verb.eq_ignore_ascii_case(cmd.name())) {
+            debug!("Rejected disabled command {}", cmd.name());
+            return Some(response::NotImplementedCommand502::new("Command disabled.").to_string());
+        }
+        if spec.login_required && !matches!(self.login_status, LoginStatus::Loggedin(_)) {
+            let name = cmd.name().to_ascii_uppercase();
+            debug!("User not logged in, rejected {name:}.");
+            return Some(response::NotLoggedin530::new(format!("Please login with USER and PASS before using {name:}")).to_string());
+        }
+        let middlewares = self.middlewares.clone();
+        for middleware in middlewares.iter() {
+            if let Some(resp) = middleware(self, cmd) {
+                return Some(resp);
+            }
+        }
+        // the parser already guarantees this, but commands can also be built by code, e.g. middlewares and tests
+        if cmd.get_args().len() < spec.min_args {
+            debug!("Rejected {} with {} of {} arguments", cmd.name(), cmd.get_args().len(), spec.min_args);
+            return Some(response::InvalidParameter501::new("Invalid number of arguments.").to_string());
+        }
+        None
+    }
+
+    fn abandon_data_connection(&mut self) {
+        if !matches!(self.transfer_mode, TransferMode::NotSpecified) {
+            debug!("Abandoned pending data connection {:?}.", self.transfer_mode);
+            self.transfer_mode = TransferMode::NotSpecified;
+        }
+    }
+}
+
+/// Commands of RFC 959, the ones not in this list are extensions
+const RFC959_COMMANDS: &[&str] = &[
+    "USER", "PASS", "ACCT", "CWD", "CDUP", "SMNT", "QUIT", "REIN", "PORT", "PASV", "TYPE", "STRU", "MODE", "RETR", "STOR",
+    "STOU", "APPE", "ALLO", "REST", "RNFR", "RNTO", "ABOR", "DELE", "RMD", "MKD", "PWD", "LIST", "NLST", "SITE", "SYST",
+    "STAT", "HELP", "NOOP",
+];
+
+/// Returns the verbs of the extension commands this server implements, derived from `Command` so it's always accurate
+fn extension_commands() -> Vec<String> {
+    Command::iter()
+        // only exists for testing the parser
+        .filter(|cmd| !matches!(cmd, Command::FakeCmdWithTwoArg(_)))
+        .map(|cmd| cmd.name().to_ascii_uppercase())
+        .filter(|verb| !RFC959_COMMANDS.contains(&verb.as_str()))
+        .collect()
+}
+
+/// Returns whether `cmd` only sets a parameter of the next transfer, so a pending data connection outlives it
+fn keeps_data_connection(cmd: &Command) -> bool {
+    matches!(cmd, Command::Pasv(_) | Command::Spsv(_) | Command::Port(_) | Command::Type(_) | Command::Rest(_))
+}
+
+#[cfg(test)]
+mod session_test {
+    use super::*;
+    use crate::{integration_test::utils::*, response, integration_test::{USERNAME, PASSWORD}};
+    mod setup {
+        use super::*;
+        use crate::integration_test::TestClient;
+        use std::{io, net::TcpListener};
+
+        /// create a TestClient and a Session over a loopback connection of their own
+        pub fn setup_client_and_session_unlogged() -> (TestClient, Session) {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let client = TestClient::connect(listener.local_addr().unwrap()).unwrap();
+            let (stream, _) = listener.accept().unwrap();
+            let session = Session::new(stream, ServerContext::new(Arc::new(ServerConfig::default()))).unwrap();
+            (client, session)
+        }
+
+        /// create a Session without client, for tests that only look at replies of exec_cmd
+        pub fn setup_session_unlogged() -> Session {
+            Session::with_streams(io::empty(), io::sink(), Arc::new(ServerConfig::default()))
+        }
+
+        pub fn setup_session_and_login() -> Session {
+            let mut session = setup_session_unlogged();
+            session.exec_user(&Command::User(vec![USERNAME.to_string()])).unwrap();
+            session.exec_pass(&Command::Pass(vec![PASSWORD.to_string()])).unwrap();
+            session
+        }
+
+        /// create a TestClient and a Session, the client is connected to the session
+        pub fn setup_client_and_session_and_login() -> (TestClient, Session) {
+            let (client, mut session) = setup_client_and_session_unlogged();
+            session.exec_user(&Command::User(vec![USERNAME.to_string()])).unwrap();
+            session.exec_pass(&Command::Pass(vec![PASSWORD.to_string()])).unwrap();   
+            (client, session)
+        }
+    }
+
+    #[test]
+    fn test_rest_applies_to_next_command_only() {
+        let mut session = setup::setup_session_and_login();
+
+        assert!(session.exec_cmd(Command::Rest(vec!["100".into()])).unwrap().starts_with("350"));
+        assert_eq!(session.restart_offset, 100);
+        session.exec_cmd(Command::Noop(vec![])).unwrap();
+        assert_eq!(session.restart_offset, 0);
+
+        session.exec_cmd(Command::Rest(vec!["100".into()])).unwrap();
+        session.exec_cmd(Command::Rest(vec!["0".into()])).unwrap();
+        assert_eq!(session.restart_offset, 0);
+
+        // a rejected REST doesn't keep the previous offset
+        for arg in ["-1", "abc"] {
+            session.exec_cmd(Command::Rest(vec!["100".into()])).unwrap();
+            assert!(session.exec_cmd(Command::Rest(vec![arg.into()])).unwrap().starts_with("501"));
+            assert_eq!(session.restart_offset, 0, "{arg}");
+        }
+    }
+
+    #[test]
+    fn test_session_ids_are_unique() {
+        let first = setup::setup_session_unlogged();
+        let second = setup::setup_session_unlogged();
+        assert!(second.id() > first.id());
+    }
+
+    #[test]
+    fn test_message_catalog() {
+        let config = Arc::new(ServerConfig {
+            message_catalog: [
+                ("Greeting220".to_string(), "Bienvenue.".to_string()),
+                ("NotLoggedin530".to_string(), "Connectez-vous.".to_string()),
+            ]
+            .into(),
+            ..Default::default()
+        });
+        let mut session = Session::with_streams(io::empty(), io::sink(), config);
+        assert_string_trim_eq(session.greeting(), "220 Bienvenue.");
+        // replies with a custom message aren't replaced
+        assert!(session.exec_cmd(Command::List(vec![])).unwrap().starts_with("530 Please login"));
+        // sessions of another server don't see the catalog
+        assert_eq!(setup::setup_session_unlogged().greeting(), response::Greeting220::default().to_string());
+    }
+
+    #[test]
+    fn test_greeting() {
+        let mut session = setup::setup_session_unlogged();
+        assert_eq!(session.greeting(), response::Greeting220::default().to_string());
+
+        session.config = Arc::new(ServerConfig {
+            greeting_advertises_version: true,
+            ..Default::default()
+        });
+        let greeting = session.greeting();
+        assert!(greeting.starts_with(&format!("220 rust-ftp {} ready (", env!("CARGO_PKG_VERSION"))), "{greeting}");
+        for verb in ["SPSV", "FEAT", "CLNT", "HOST"] {
+            assert!(greeting.contains(verb), "{greeting}");
+        }
+        for verb in ["PASV", "LIST", "FAKECMDWITHTWOARG"] {
+            assert!(!greeting.contains(verb), "{greeting}");
+        }
+
+        // the operator's greeting wins over the banner
+        session.catalog = Arc::new(MessageCatalog::from([("Greeting220".to_string(), "Authorized use only.".to_string())]));
+        assert_eq!(session.greeting(), "220 Authorized use only.\r\n");
+    }
+
+    #[test]
+    fn test_disabled_commands() {
+        let mut session = setup::setup_session_and_login();
+        session.config = Arc::new(ServerConfig {
+            disabled_commands: ["list".to_string(), "SITE".to_string()].into(),
+            ..Default::default()
+        });
+
+        session.exec_cmd(Command::Pasv(vec![])).unwrap();
+        assert_string_trim_eq(session.exec_cmd(Command::List(vec![])).unwrap(), "502 Command disabled.");
+        assert!(session.exec_cmd(Command::Site(vec!["UMASK".into()])).unwrap().starts_with("502"));
+        assert!(session.exec_cmd(Command::Noop(vec![])).unwrap().starts_with("200"));
+    }
+
+    #[test]
+    fn test_middleware_short_circuits() {
+        fn veto_noop_stat(_: &mut Session, cmd: &Command) -> Option<String> {
+            matches!(cmd, Command::Noop(_) | Command::Stat(_)).then(|| response::ActionNotTaken450::default().to_string())
+        }
+        fn never(_: &mut Session, _: &Command) -> Option<String> {
+            None
+        }
+        let mut session = setup::setup_session_unlogged();
+        session.middlewares = Arc::new(vec![never, veto_noop_stat]);
+
+        assert!(session.exec_cmd(Command::Noop(vec![])).unwrap().starts_with("450"));
+        assert!(session.exec_cmd(Command::Feat(vec![])).unwrap().starts_with("211"));
+        // the login check runs before middlewares, they never see commands a user not logged in can't run
+        assert!(session.exec_cmd(Command::Stat(vec![])).unwrap().starts_with("530"));
+
+        let mut session = setup::setup_session_and_login();
+        session.middlewares = Arc::new(vec![never, veto_noop_stat]);
+        assert!(session.exec_cmd(Command::Stat(vec![])).unwrap().starts_with("450"));
+    }
+
+    #[test]
+    fn test_retry_interrupted() {
+        let mut calls = 0;
+        let result = retry_interrupted(|| {
+            calls += 1;
+            if calls < 3 {
+                return Err(io::Error::from(ErrorKind::Interrupted));
+            }
+            Ok(calls)
+        });
+        assert_eq!(result.unwrap(), 3);
+
+        let result: io::Result<()> = retry_interrupted(|| Err(io::Error::from(ErrorKind::ConnectionAborted)));
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::ConnectionAborted);
+    }
+
+    #[test]
+    fn test_address_to_comma_address() {
+        assert_eq!(address_to_comma_address("127.0.0.1").unwrap(), "127,0,0,1");
+        assert_eq!(address_to_comma_address("192.168.100.200").unwrap(), "192,168,100,200");
+        assert!(address_to_comma_address("::1").is_none());
+        assert!(address_to_comma_address("localhost").is_none());
+        assert!(address_to_comma_address("256.0.0.1").is_none());
+    }
+
+    #[test]
+    fn test_list_path() {
+        assert_eq!(list_path(""), ".");
+        assert_eq!(list_path("-la"), ".");
+        assert_eq!(list_path("-l"), ".");
+        assert_eq!(list_path("-a dir"), "dir");
+        assert_eq!(list_path("-al /some/dir"), "/some/dir");
+        assert_eq!(list_path("-alR"), ".");
+        assert_eq!(list_path("/pub"), "/pub");
+        assert_eq!(list_path("-"), "-");
+        assert_eq!(list_path("-la 目录/😀 文件.txt"), "目录/😀 文件.txt");
+        assert_eq!(list_path("-weird-dirname"), "-weird-dirname");
+        assert_eq!(list_path("dir name"), "dir name");
+    }
+
+    #[test]
+    fn test_visible_entries() {
+        let entries = [".", "..", ".hidden", "file", ".ssh", "a.b"];
+        assert_eq!(visible_entries(&entries, false), entries);
+        assert_eq!(visible_entries(&entries, true), [".", "..", "file", "a.b"]);
+    }
+
+    #[test]
+    fn test_format_listing() {
+        let entries = [".", "..", "a", "b"];
+        assert_eq!(format_listing(&entries, 0), ".\r\n..\r\na\r\nb\r\n");
+        assert_eq!(format_listing(&entries, 4), ".\r\n..\r\na\r\nb\r\n");
+        assert_eq!(format_listing(&entries, 2), ".\r\n..\r\n... listing truncated after 2 of 4 entries\r\n");
+        assert_eq!(format_listing(&[], 2), "");
+    }
+
+    #[test]
+    fn test_format_dos_entry() {
+        let at = |secs| std::time::UNIX_EPOCH + Duration::from_secs(secs);
+        assert_eq!(format_dos_entry("pub", true, 0, at(0)), "01-01-70  12:00AM       <DIR>          pub");
+        assert_eq!(format_dos_entry("a.txt", false, 689, at(1644592800)), "02-11-22  03:20PM                  689 a.txt");
+        // leap day, noon and the last minute of a century
+        assert_eq!(format_dos_entry("f", false, 1, at(951825600)), "02-29-00  12:00PM                    1 f");
+        assert_eq!(format_dos_entry("f", false, 1, at(4102444740)), "12-31-99  11:59PM                    1 f");
+    }
+
+    #[test]
+    fn test_normalize_line_endings() {
+        assert_eq!(normalize_line_endings("a\r\nb\nc", TransferType::Ascii), "a\r\nb\r\nc\r\n");
+        assert_eq!(normalize_line_endings("a\r\nb\nc\n", TransferType::Binary), "a\nb\nc\n");
+        assert_eq!(normalize_line_endings("", TransferType::Ascii), "");
+    }
+
+    #[test]
+    fn test_create_session() {
+        let (_, _) = setup::setup_client_and_session_and_login();
+    }
+
+    #[test]
+    fn test_send_msg() {
+        let (mut client, mut session) = setup::setup_client_and_session_and_login();
+
+        let msg = "message";
+        session.send_msg_check_crlf(msg).unwrap();
+        assert_string_trim_eq(client.get_msg_trimed().unwrap(), msg);
+    }
+
+    #[test]
+    fn test_send_resp() {
+        let (mut client, mut session) = setup::setup_client_and_session_and_login();
+
+        session
+            .send_msg_check_crlf(response::UnknownRespWithoutDefaultMessage999::new(
+                "message",
+            ))
+            .unwrap();
+        assert_string_trim_eq(client.get_msg_trimed().unwrap(), "999 message");
+    }
+
+    #[test]
+    fn test_get_cmd() {
+        let (mut client, mut session) = setup::setup_client_and_session_and_login();
+
+        client.send_msg_add_crlf("QUIT arg").unwrap();
+        let cmd = session.get_cmd().unwrap();
+        assert!(cmd.is_ok());
+        assert!(matches!(cmd.unwrap(), Command::Quit(_)));
+    }
+
+    #[test]
+    fn test_get_cmd_telnet_commands() {
+        let (mut client, mut session) = setup::setup_client_and_session_and_login();
+
+        // IAC IP IAC DM, as sent before ABOR
+        client.cmd_writer.write_all(b"\xff\xf4\xff\xf2QUIT\r\n").unwrap();
+        client.cmd_writer.flush().unwrap();
+        assert!(matches!(session.get_cmd().unwrap().unwrap(), Command::Quit(_)));
+    }
+
+    #[test]
+    fn test_get_cmd_blank_line() {
+        let (mut client, mut session) = setup::setup_client_and_session_and_login();
+
+        client.send_msg_add_crlf("").unwrap();
+        client.send_msg_add_crlf("QUIT").unwrap();
+        assert!(matches!(session.get_cmd().unwrap().unwrap(), Command::Quit(_)));
+
+        session.config = Arc::new(ServerConfig {
+            ignore_blank_lines: false,
+            ..Default::default()
+        });
+        client.send_msg_add_crlf("").unwrap();
+        let err = session.get_cmd().unwrap().err().unwrap();
+        assert!(err.to_string().starts_with("500"));
+    }
+
+    #[test]
+    fn test_get_cmd_pipelined() {
+        let (mut client, mut session) = setup::setup_client_and_session_and_login();
+
+        // off by default
+        client.send_msg_add_crlf("NOOP\r\nNOOP").unwrap();
+        assert!(session.get_cmd().unwrap().is_ok());
+        assert!(session.get_cmd().unwrap().is_ok());
+
+        session.config = Arc::new(ServerConfig {
+            reject_pipelined_commands: true,
+            ..Default::default()
+        });
+        client.send_msg_add_crlf("NOOP\r\nNOOP\r\nNOOP").unwrap();
+        assert!(session.get_cmd().unwrap().is_ok());
+        for _ in 0..2 {
+            let err = session.get_cmd().unwrap().err().unwrap();
+            assert!(err.to_string().starts_with("503"), "{err}");
+            assert!(session.rejected_pipelined());
+        }
+        // sent after the reply
+        client.send_msg_add_crlf("NOOP").unwrap();
+        assert!(session.get_cmd().unwrap().is_ok());
+        assert!(!session.rejected_pipelined());
+    }
+
+    #[test]
+    fn test_data_commands_require_login() {
+        for cmd in Command::iter() {
+            let spec = Session::command_spec(&cmd);
+            assert!(!spec.data_connection || spec.login_required, "{} should require login", cmd.name());
+        }
+    }
+
+    #[test]
+    fn test_missing_arguments_rejected() {
+        let mut session = setup::setup_session_and_login();
+
+        // commands built by iter have no argument
+        let needing_args = Command::iter().filter(|cmd| Session::command_spec(cmd).min_args > 0).collect::<Vec<_>>();
+        assert!(needing_args.len() >= 5);
+        for cmd in needing_args {
+            let name = cmd.name();
+            let resp = session.exec_cmd(cmd).unwrap();
+            assert_string_trim_eq(&resp, "501 Invalid number of arguments.");
+            assert!(matches!(session.login_status, LoginStatus::Loggedin(_)), "{name} changed login");
+        }
+    }
+
+    #[test]
+    fn test_pre_login_allowlist() {
+        let allowed = Command::iter()
+            .filter(|cmd| !Session::command_spec(cmd).login_required)
+            .map(|cmd| cmd.name().to_ascii_uppercase())
+            .collect::<Vec<_>>();
+        assert_eq!(allowed, ["QUIT", "USER", "PASS", "FEAT", "NOOP", "CLNT", "HOST", "REIN"]);
+    }
+
+    #[test]
+    fn test_unlogged_commands_rejected() {
+        let mut session = setup::setup_session_unlogged();
+
+        for cmd in Command::iter().filter(|cmd| Session::command_spec(cmd).login_required) {
+            let name = cmd.name().to_ascii_uppercase();
+            let resp = session.exec_cmd(cmd).unwrap();
+            assert_string_trim_eq(&resp, format!("530 Please login with USER and PASS before using {name}"));
+        }
+    }
+
+    /// the code each command replies on success, so that e.g. a data command can't silently reply 250
+    ///     or a control connection only command 226. The match is exhaustive, so every new command must be added
+    #[test]
+    fn test_success_codes() {
+        for template in Command::iter() {
+            let name = template.name();
+            let mut session = setup::setup_session_and_login();
+            let mut _data_conn = None;
+            let (cmd, code) = match template {
+                // QUIT sends its 221 by itself and closes the connection
+                Command::Quit(_) | Command::FakeCmdWithTwoArg(_) => continue,
+                Command::User(_) => {
+                    session = setup::setup_session_unlogged();
+                    (Command::User(vec![USERNAME.into()]), 331)
+                }
+                Command::Pass(_) => {
+                    session = setup::setup_session_unlogged();
+                    session.exec_cmd(Command::User(vec![USERNAME.into()])).unwrap();
+                    (Command::Pass(vec![PASSWORD.into()]), 230)
+                }
+                Command::Pasv(_) => (Command::Pasv(vec![]), 227),
+                Command::Spsv(_) => (Command::Spsv(vec![]), 227),
+                Command::Port(_) => (Command::Port(vec!["127,0,0,1,4,1".into()]), 200),
+                Command::List(_) => {
+                    session.exec_cmd(Command::Pasv(vec![])).unwrap();
+                    _data_conn = Some(test_data_transfer::utils::data_conn_client(&session));
+                    (Command::List(vec![]), 226)
+                }
+                Command::Site(_) => (Command::Site(vec!["UMASK 022".into()]), 200),
+                Command::Feat(_) => (Command::Feat(vec![]), 211),
+                Command::Noop(_) => (Command::Noop(vec![]), 200),
+                Command::Stat(_) => (Command::Stat(vec![]), 211),
+                Command::Clnt(_) => (Command::Clnt(vec!["client".into()]), 200),
+                Command::Type(_) => (Command::Type(vec!["I".into()]), 200),
+                Command::Rein(_) => (Command::Rein(vec![]), 220),
+                Command::Rest(_) => (Command::Rest(vec!["0".into()]), 350),
+                Command::Host(_) => {
+                    session = setup::setup_session_unlogged();
+                    session.config = Arc::new(ServerConfig {
+                        virtual_hosts: std::collections::HashMap::from([("ftp.example.com".into(), VirtualHost::default())]),
+                        ..Default::default()
+                    });
+                    (Command::Host(vec!["ftp.example.com".into()]), 220)
+                }
+            };
+            let resp = session.exec_cmd(cmd).unwrap();
+            // the last line of a multi-line reply carries the code
+            let last_line = resp.trim_end().lines().last().unwrap();
+            assert!(last_line.starts_with(&format!("{code} ")), "{name} replied {resp}");
+        }
+    }
+
+    #[test]
+    fn test_history_entry() {
+        assert_eq!(history_entry(&Command::Noop(vec![])), "NOOP");
+        assert_eq!(history_entry(&Command::List(vec!["-la /pub".into()])), "LIST -la /pub");
+        assert_eq!(history_entry(&Command::Pass(vec!["secret".into()])), "PASS ****");
+    }
+
+    #[test]
+    fn test_stat_history() {
+        let mut session = setup::setup_session_unlogged();
+
+        session.exec_cmd(Command::User(vec![USERNAME.into()])).unwrap();
+        session.exec_cmd(Command::Pass(vec!["secret".into()])).unwrap();
+        session.exec_cmd(Command::User(vec![USERNAME.into()])).unwrap();
+        session.exec_cmd(Command::Pass(vec![PASSWORD.into()])).unwrap();
+        let resp = session.exec_cmd(Command::Stat(vec![])).unwrap();
+        assert!(resp.ends_with("211 End\r\n"), "{resp}");
+        assert!(resp.contains(&format!("211-Logged in as {USERNAME}\r\n")), "{resp}");
+        assert!(resp.contains("211-    USER anonymous\r\n211-    PASS ****\r\n"), "{resp}");
+        assert!(!resp.contains("secret"), "{resp}");
+
+        // only the last HISTORY_LEN commands are kept
+        for _ in 0..HISTORY_LEN {
+            session.exec_cmd(Command::Noop(vec![])).unwrap();
+        }
+        let resp = session.exec_cmd(Command::Stat(vec![])).unwrap();
+        assert_eq!(resp.matches("211-    NOOP\r\n").count(), HISTORY_LEN, "{resp}");
+        assert!(!resp.contains("PASS"), "{resp}");
+    }
+
+    #[test]
+    fn test_clnt() {
+        let mut session = setup::setup_session_unlogged();
+
+        let resp = session.exec_cmd(Command::parse("CLNT NcFTP 3.2.6 macosx10.15").unwrap()).unwrap();
+        assert!(resp.starts_with("200"), "{resp}");
+        assert_eq!(session.client_name.as_deref(), Some("NcFTP 3.2.6 macosx10.15"));
+    }
+
+    #[test]
+    fn test_type() {
+        let mut session = setup::setup_session_and_login();
+        assert_eq!(session.transfer_type, TransferType::Ascii);
+
+        assert_string_trim_eq(session.exec_cmd(Command::Type(vec!["i".into()])).unwrap(), "200 Switching to BINARY mode.");
+        assert_eq!(session.transfer_type, TransferType::Binary);
+        assert!(session.exec_cmd(Command::Stat(vec![])).unwrap().contains("211-TYPE: BINARY\r\n"));
+        assert_string_trim_eq(session.exec_cmd(Command::Type(vec!["A".into()])).unwrap(), "200 Switching to ASCII mode.");
+        assert_eq!(session.transfer_type, TransferType::Ascii);
+
+        assert!(session.exec_cmd(Command::Type(vec!["a n".into()])).unwrap().starts_with("200"));
+        assert!(session.exec_cmd(Command::Stat(vec![])).unwrap().contains("211-TYPE: ASCII, FORM: Nonprint\r\n"));
+        session.exec_cmd(Command::Type(vec!["I".into()])).unwrap();
+        for arg in ["A T", "A C"] {
+            assert_string_trim_eq(session.exec_cmd(Command::Type(vec![arg.into()])).unwrap(), "504 Unsupported ASCII form.");
+        }
+        assert_eq!(session.transfer_type, TransferType::Binary);
+        session.exec_cmd(Command::Type(vec!["A".into()])).unwrap();
+
+        for arg in ["E", "L 8", "X", "A N X", "I N"] {
+            assert!(session.exec_cmd(Command::Type(vec![arg.into()])).unwrap().starts_with("504"), "{arg}");
+        }
+        assert_eq!(session.transfer_type, TransferType::Ascii);
+    }
+
+    #[test]
+    fn test_type_default() {
+        let config = Arc::new(ServerConfig {
+            default_transfer_type: TransferType::Binary,
+            ..Default::default()
+        });
+        let session = Session::with_streams(std::io::empty(), std::io::sink(), config);
+        assert_eq!(session.transfer_type, TransferType::Binary);
+    }
+
+    #[test]
+    fn test_exec_quit() {
+        let mut session = setup::setup_session_and_login();
+
+        // Quit will return an Err, thus the infinite loop in serve will break and Session will be dropped
+        //      thus the stream in Session will be automaticly closed
+        assert!(session.exec_cmd(Command::Quit(vec![])).is_err());
+    }
+
+    mod test_site {
+        use super::*;
+
+        #[test]
+        fn test_feat_lists_site_commands() {
+            let mut session = setup::setup_session_unlogged();
+            session.config = Arc::new(ServerConfig {
+                site_exec_enabled: true,
+                ..Default::default()
+            });
+
+            let resp = session.exec_cmd(Command::Feat(vec![])).unwrap();
+            assert!(resp.starts_with("211-"));
+            assert!(resp.ends_with("211 End\r\n"));
+            for (name, _) in SITE_COMMANDS {
+                assert!(resp.contains(&format!("\r\n SITE {name:}\r\n")), "{name} missing in {resp}");
+            }
+        }
+
+        #[test]
+        fn test_feat_omits_disabled_site_commands() {
+            let mut session = setup::setup_session_unlogged();
+
+            // SITE EXEC is disabled by default
+            let resp = session.exec_cmd(Command::Feat(vec![])).unwrap();
+            assert!(!resp.contains("SITE EXEC"), "{resp}");
+            assert!(resp.contains("\r\n SITE UMASK\r\n"), "{resp}");
+
+            session.config = Arc::new(ServerConfig {
+                site_exec_enabled: true,
+                disabled_commands: ["site".to_string()].into(),
+                ..Default::default()
+            });
+            let resp = session.exec_cmd(Command::Feat(vec![])).unwrap();
+            assert!(!resp.contains("SITE"), "{resp}");
+        }
+
+        #[test]
+        fn test_site_unknown() {
+            let mut session = setup::setup_session_and_login();
+
+            assert!(session.exec_cmd(Command::Site(vec!["NONE".into()])).unwrap().starts_with("500"));
+        }
+
+        #[test]
+        fn test_site_umask() {
+            let mut session = setup::setup_session_and_login();
+
+            assert_string_trim_eq(
+                session.exec_cmd(Command::Site(vec!["UMASK".into()])).unwrap(),
+                "200 Your current UMASK is 022",
+            );
+            assert_string_trim_eq(
+                session.exec_cmd(Command::Site(vec!["umask 027".into()])).unwrap(),
+                "200 UMASK set to 027",
+            );
+            assert_eq!(session.umask, 0o027);
+            assert_string_trim_eq(
+                session.exec_cmd(Command::Site(vec!["UMASK".into()])).unwrap(),
+                "200 Your current UMASK is 027",
+            );
+        }
+
+        #[test]
+        fn test_site_idle() {
+            let (_, mut session) = setup::setup_client_and_session_and_login();
+
+            assert_string_trim_eq(
+                session.exec_cmd(Command::Site(vec!["IDLE".into()])).unwrap(),
+                "200 Current idle timeout is 300",
+            );
+            assert_string_trim_eq(
+                session.exec_cmd(Command::Site(vec!["IDLE 60".into()])).unwrap(),
+                "200 Idle timeout set to 60",
+            );
+            assert_eq!(session.cmd_stream.as_ref().unwrap().read_timeout().unwrap(), Some(Duration::from_secs(60)));
+            // clamped to max_idle_timeout
+            assert_string_trim_eq(
+                session.exec_cmd(Command::Site(vec!["IDLE 99999".into()])).unwrap(),
+                "200 Idle timeout set to 7200",
+            );
+            for arg in ["IDLE 0", "IDLE -1", "IDLE abc"] {
+                assert!(session.exec_cmd(Command::Site(vec![arg.into()])).unwrap().starts_with("501"));
+            }
+            assert_eq!(session.idle_timeout, Duration::from_secs(7200));
+        }
+
+        #[test]
+        fn test_idle_timeout() {
+            let (mut client, mut session) = setup::setup_client_and_session_and_login();
+            session.cmd_stream.as_ref().unwrap().set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+
+            assert!(session.get_cmd().is_err());
+            assert_eq!(client.get_msg_code().unwrap(), 421);
+        }
+
+        #[test]
+        fn test_site_listfmt() {
+            let mut session = setup::setup_session_and_login();
+
+            assert_string_trim_eq(session.exec_cmd(Command::Site(vec!["LISTFMT".into()])).unwrap(), "200 LIST format is unix");
+            assert_string_trim_eq(session.exec_cmd(Command::Site(vec!["LISTFMT DOS".into()])).unwrap(), "200 LIST format set to dos");
+            assert_eq!(session.list_format, ListFormat::Dos);
+            assert!(session.exec_cmd(Command::Site(vec!["LISTFMT vms".into()])).unwrap().starts_with("501"));
+            assert_eq!(session.list_format, ListFormat::Dos);
+        }
+
+        #[test]
+        fn test_site_exec() {
+            let mut session = setup::setup_session_and_login();
+
+            // disabled by default even for allowed commands
+            session.config = Arc::new(ServerConfig {
+                site_exec_allowed: vec![vec!["/bin/echo".into(), "hello".into()]],
+                ..Default::default()
+            });
+            assert!(session.exec_cmd(Command::Site(vec!["EXEC /bin/echo hello".into()])).unwrap().starts_with("502"));
+
+            session.config = Arc::new(ServerConfig {
+                site_exec_enabled: true,
+                site_exec_allowed: vec![vec!["/bin/echo".into(), "hello".into()], vec!["echo".into(), "hello".into()]],
+                ..Default::default()
+            });
+            assert_eq!(
+                session.exec_cmd(Command::Site(vec!["EXEC /bin/echo   hello".into()])).unwrap(),
+                "200-hello\r\n200 Exit status 0\r\n"
+            );
+            let rejected = ["EXEC", "EXEC /bin/echo", "EXEC /bin/echo bye", "EXEC /bin/echo hello; id", "EXEC /bin/echo hello extra"];
+            for arg in rejected {
+                assert!(session.exec_cmd(Command::Site(vec![arg.into()])).unwrap().starts_with("550"), "{arg}");
+            }
+            // relative programs never run, even if they slipped into the whitelist
+            assert_string_trim_eq(session.exec_cmd(Command::Site(vec!["EXEC echo hello".into()])).unwrap(), "550 Command not allowed.");
+        }
+
+        #[test]
+        fn test_site_exec_failure() {
+            let mut session = setup::setup_session_and_login();
+            session.config = Arc::new(ServerConfig {
+                site_exec_enabled: true,
+                site_exec_allowed: vec![vec!["/bin/false".into()], vec!["/bin/sleep".into(), "10".into()]],
+                command_timeout: Some(Duration::from_millis(100)),
+                ..Default::default()
+            });
+            assert_eq!(session.exec_cmd(Command::Site(vec!["EXEC /bin/false".into()])).unwrap(), "550 Exit status 1\r\n");
+
+            let start = Instant::now();
+            let resp = session.exec_cmd(Command::Site(vec!["EXEC /bin/sleep 10".into()])).unwrap();
+            assert!(resp.starts_with("550 Killed after"), "{resp}");
+            assert!(start.elapsed() < Duration::from_secs(5));
+        }
+
+        #[test]
+        fn test_site_umask_invalid() {
+            let mut session = setup::setup_session_and_login();
+
+            for arg in ["UMASK 089", "UMASK abc", "UMASK 1777", "UMASK -1"] {
+                assert!(session.exec_cmd(Command::Site(vec![arg.into()])).unwrap().starts_with("501"));
+            }
+            assert_eq!(session.umask, 0o022);
+        }
+    }
+
+    mod test_loggin {
+        use super::*;
+
+
+        #[test]
         fn test_unlogged() {
-            let (_, session) = setup::setup_client_and_session_unlogged();
+            let session = setup::setup_session_unlogged();
 
             assert_eq!(session.login_status, LoginStatus::Unloggedin);
         }
@@ -337,7 +1962,7 @@ mod session_test {
             use super::*;
             #[test]
             fn test_exec_user_unlogged() {
-                let (_, mut session) = setup::setup_client_and_session_unlogged();
+                let mut session = setup::setup_session_unlogged();
 
                 session
                     .exec_cmd(Command::User(vec![USERNAME.into()]))
@@ -347,7 +1972,7 @@ mod session_test {
 
             #[test]
             fn test_exec_user_username() {
-                let (_, mut session) = setup::setup_client_and_session_and_login();
+                let mut session = setup::setup_session_and_login();
 
                 session.login_status = LoginStatus::Username("oldusername".into());
                 session
@@ -363,7 +1988,7 @@ mod session_test {
 
             #[test]
             fn test_exec_user_loggedin() {
-                let (_, mut session) = setup::setup_client_and_session_and_login();
+                let mut session = setup::setup_session_and_login();
 
                 session.login_status = LoginStatus::Loggedin("oldusername".into());
                 session
@@ -378,12 +2003,84 @@ mod session_test {
             }
         }
 
+        mod test_host {
+            use super::*;
+            use std::collections::HashMap;
+
+            fn setup_session_with_hosts() -> Session {
+                let mut session = setup::setup_session_unlogged();
+                session.config = Arc::new(ServerConfig {
+                    virtual_hosts: HashMap::from([
+                        (
+                            "ftp.example.com".to_string(),
+                            VirtualHost {
+                                banner: Some("Welcome to example.".into()),
+                                allow_anonymous: false,
+                                ..Default::default()
+                            },
+                        ),
+                        ("plain.example.com".to_string(), VirtualHost::default()),
+                    ]),
+                    ..Default::default()
+                });
+                session
+            }
+
+            #[test]
+            fn test_exec_host() {
+                let mut session = setup_session_with_hosts();
+
+                let resp = session.exec_cmd(Command::Host(vec!["FTP.example.com".into()])).unwrap();
+                assert_string_trim_eq(resp, "220 Welcome to example.");
+                // the virtual host's auth options replace the server's
+                session.exec_cmd(Command::User(vec![USERNAME.into()])).unwrap();
+                let resp = session.exec_cmd(Command::Pass(vec![PASSWORD.into()])).unwrap();
+                assert_string_trim_eq(resp, "530 Anonymous login is disabled.");
+
+                let mut session = setup_session_with_hosts();
+                let resp = session.exec_cmd(Command::Host(vec!["plain.example.com".into()])).unwrap();
+                assert_string_trim_eq(resp, response::Greeting220::default().to_string());
+            }
+
+            #[test]
+            fn test_exec_host_default_greeting() {
+                let mut session = setup_session_with_hosts();
+                session.config = Arc::new(ServerConfig {
+                    greeting_advertises_version: true,
+                    virtual_hosts: session.config.virtual_hosts.clone(),
+                    ..Default::default()
+                });
+
+                let resp = session.exec_cmd(Command::Host(vec!["plain.example.com".into()])).unwrap();
+                assert_eq!(resp, session.greeting());
+            }
+
+            #[test]
+            fn test_exec_host_unknown() {
+                let mut session = setup_session_with_hosts();
+
+                assert!(session.exec_cmd(Command::Host(vec!["other.example.com".into()])).unwrap().starts_with("504"));
+                assert!(session.virtual_host.is_none());
+            }
+
+            #[test]
+            fn test_exec_host_after_user() {
+                let mut session = setup_session_with_hosts();
+                session.exec_cmd(Command::User(vec![USERNAME.into()])).unwrap();
+                assert!(session.exec_cmd(Command::Host(vec!["ftp.example.com".into()])).unwrap().starts_with("503"));
+
+                let mut session = setup::setup_session_and_login();
+                assert!(session.exec_cmd(Command::Host(vec!["ftp.example.com".into()])).unwrap().starts_with("503"));
+                assert!(session.virtual_host.is_none());
+            }
+        }
+
         mod test_pass {
             use super::*;
 
             #[test]
             fn test_exec_pass_unlogged() {
-                let (_, mut session) = setup::setup_client_and_session_unlogged();
+                let mut session = setup::setup_session_unlogged();
 
                 session
                     .exec_cmd(Command::Pass(vec![PASSWORD.into()]))
@@ -393,7 +2090,7 @@ mod session_test {
 
             #[test]
             fn test_exec_pass_username() {
-                let (_, mut session) = setup::setup_client_and_session_and_login();
+                let mut session = setup::setup_session_and_login();
 
                 session.login_status = LoginStatus::Username(USERNAME.into());
                 session
@@ -401,6 +2098,7 @@ mod session_test {
                     .unwrap();
                 // status back to Unloggedin
                 assert_eq!(session.login_status, LoginStatus::Unloggedin);
+                assert!(session.tarpit.is_recent_offender(session.client_ip()));
 
                 session.login_status = LoginStatus::Username(USERNAME.into());
                 // right password
@@ -411,9 +2109,92 @@ mod session_test {
                 assert_eq!(session.login_status, LoginStatus::Loggedin(USERNAME.into()))
             }
 
+            #[test]
+            fn test_exec_pass_anonymous_disabled() {
+                let mut session = setup::setup_session_unlogged();
+                session.config = Arc::new(ServerConfig {
+                    allow_anonymous: false,
+                    ..Default::default()
+                });
+
+                assert!(session.exec_cmd(Command::User(vec![USERNAME.into()])).unwrap().starts_with("331"));
+                let resp = session.exec_cmd(Command::Pass(vec![PASSWORD.into()])).unwrap();
+                assert_string_trim_eq(resp, "530 Anonymous login is disabled.");
+                assert_eq!(session.login_status, LoginStatus::Unloggedin);
+
+                session.exec_cmd(Command::User(vec!["FTP".into()])).unwrap();
+                assert!(session.exec_cmd(Command::Pass(vec![PASSWORD.into()])).unwrap().starts_with("530"));
+            }
+
+            #[test]
+            fn test_exec_pass_anonymous_enabled() {
+                let mut session = setup::setup_session_unlogged();
+                assert!(session.config.allow_anonymous);
+
+                session.exec_cmd(Command::User(vec![USERNAME.into()])).unwrap();
+                assert!(session.exec_cmd(Command::Pass(vec![PASSWORD.into()])).unwrap().starts_with("230"));
+                assert_eq!(session.login_status, LoginStatus::Loggedin(USERNAME.into()));
+            }
+
+            #[test]
+            fn test_exec_pass_audit() {
+                use std::sync::Mutex;
+
+                #[derive(Debug, Default)]
+                struct RecordingSink(Mutex<Vec<(String, IpAddr, bool)>>);
+                impl AuditSink for RecordingSink {
+                    fn login_attempt(&self, username: &str, client_ip: IpAddr, success: bool) {
+                        self.0.lock().unwrap().push((username.into(), client_ip, success));
+                    }
+                }
+
+                let mut session = setup::setup_session_unlogged();
+                let sink = Arc::new(RecordingSink::default());
+                session.audit_sink = sink.clone();
+
+                session.exec_cmd(Command::User(vec!["someone".into()])).unwrap();
+                session.exec_cmd(Command::Pass(vec!["wrong".into()])).unwrap();
+                session.exec_cmd(Command::User(vec![USERNAME.into()])).unwrap();
+                session.exec_cmd(Command::Pass(vec![PASSWORD.into()])).unwrap();
+                let ip = session.client_ip();
+                assert_eq!(
+                    *sink.0.lock().unwrap(),
+                    [("someone".to_string(), ip, false), (USERNAME.to_string(), ip, true)]
+                );
+            }
+
+            #[test]
+            fn test_exec_pass_login_message() {
+                let mut session = setup::setup_session_unlogged();
+                let path = std::env::temp_dir().join(format!("rust-ftp-login-message-{}", std::process::id()));
+                std::fs::write(&path, "Welcome\nto rust-ftp\n").unwrap();
+                session.config = Arc::new(ServerConfig {
+                    login_message_path: Some(path.clone()),
+                    ..Default::default()
+                });
+
+                session.exec_cmd(Command::User(vec![USERNAME.into()])).unwrap();
+                let resp = session.exec_cmd(Command::Pass(vec![PASSWORD.into()])).unwrap();
+                assert_eq!(resp, "230-Welcome\r\n230-to rust-ftp\r\n230 Login successful.\r\n");
+                std::fs::remove_file(&path).unwrap();
+            }
+
+            #[test]
+            fn test_exec_pass_login_message_missing() {
+                let mut session = setup::setup_session_unlogged();
+                session.config = Arc::new(ServerConfig {
+                    login_message_path: Some("/nonexistent/rust-ftp-login-message".into()),
+                    ..Default::default()
+                });
+
+                session.exec_cmd(Command::User(vec![USERNAME.into()])).unwrap();
+                let resp = session.exec_cmd(Command::Pass(vec![PASSWORD.into()])).unwrap();
+                assert_string_trim_eq(resp, response::LoginSuccess230::default().to_string());
+            }
+
             #[test]
             fn test_exec_pass_loggedin() {
-                let (_, mut session) = setup::setup_client_and_session_and_login();
+                let mut session = setup::setup_session_and_login();
 
                 session.login_status = LoginStatus::Loggedin(USERNAME.into());
                 session
@@ -427,25 +2208,27 @@ mod session_test {
     }
 
     mod test_data_transfer {
+        use crate::clock::FixedClock;
         use std::{
-            thread::{self, sleep},
+            thread,
             time::Duration,
         };
 
         use super::*;
-        mod utils {
+        pub mod utils {
             use super::*;
+
+            /// accept a connection, panics rather than hangs if nobody connects in time
+            pub fn accept_in_time(listener: &TcpListener) -> (TcpStream, SocketAddr) {
+                accept_before(listener, Some(Instant::now() + Duration::from_secs(5))).unwrap()
+            }
+
             pub fn data_conn_client_server(session: &Session) -> (TcpStream, TcpStream) {
                 match &session.transfer_mode {
                     TransferMode::Pasv(port, listener) => {
-                        let port = *port;
-                        let try_conn = thread::spawn(move || {
-                            let addr = format!("127.0.0.1:{port:}");
-                            TcpStream::connect(addr).unwrap()
-                        });
-                        sleep(Duration::from_secs(1));
-                        let (server_conn, _) = listener.accept().unwrap();
-                        let client_conn = try_conn.join().unwrap();
+                        // connect completes in the backlog, before accept
+                        let client_conn = TcpStream::connect(("127.0.0.1", *port)).unwrap();
+                        let (server_conn, _) = accept_in_time(listener);
                         (client_conn, server_conn)
                     }
                     _ => {
@@ -453,35 +2236,26 @@ mod session_test {
                     }
                 }
             }
-    
+
             pub fn data_conn_client(session: &Session) -> TcpStream {
                 match &session.transfer_mode {
-                    TransferMode::Pasv(port, _) => {
-                        let port = *port;
-                        let try_conn = thread::spawn(move || {
-                            let addr = format!("127.0.0.1:{port:}");
-                            TcpStream::connect(addr).unwrap()
-                        });
-                        sleep(Duration::from_secs(1));
-                        try_conn.join().unwrap()
-                    }
+                    TransferMode::Pasv(port, _) => TcpStream::connect(("127.0.0.1", *port)).unwrap(),
                     _ => {
                         panic!()
                     }
-                } 
+                }
             }
-    
         }
         #[test]
         fn test_no_mode() {
-            let (_, session) = setup::setup_client_and_session_and_login();
+            let session = setup::setup_session_and_login();
 
             assert!(matches!(session.transfer_mode, TransferMode::NotSpecified));
         }
 
         #[test]
         fn test_pasv() {
-            let (_, mut session) = setup::setup_client_and_session_and_login();
+            let mut session = setup::setup_session_and_login();
 
             assert!(session.exec_cmd(Command::Pasv(vec![])).unwrap().starts_with("227"));
             assert!(matches!(session.transfer_mode, TransferMode::Pasv(_, _)));
@@ -490,9 +2264,192 @@ mod session_test {
             crate::integration_test::utils::test_connect(&mut server_conn, &mut client_conn)
         }
 
+        #[test]
+        fn test_spsv() {
+            let mut session = setup::setup_session_and_login();
+
+            let resp = session.exec_cmd(Command::Spsv(vec![])).unwrap();
+            let TransferMode::Pasv(port, _) = session.transfer_mode else {
+                unreachable!()
+            };
+            assert_eq!(resp, format!("227 ({port})\r\n"));
+
+            let _client_conn = utils::data_conn_client(&session);
+            assert!(session.exec_cmd(Command::List(vec![])).unwrap().starts_with("226"));
+        }
+
+        #[test]
+        fn test_pasv_port_offset() {
+            let mut session = setup::setup_session_and_login();
+            session.config = Arc::new(ServerConfig {
+                pasv_port_offset: 1,
+                ..Default::default()
+            });
+
+            let resp = session.exec_cmd(Command::Pasv(vec![])).unwrap();
+            let bound_port = if let TransferMode::Pasv(port, _) = &session.transfer_mode {
+                *port
+            } else {
+                unreachable!()
+            };
+            let advertised_addr = crate::integration_test::utils::parse_pasv_response(&resp);
+            assert!(advertised_addr.ends_with(&format!(":{}", bound_port + 1)));
+        }
+
+        #[test]
+        fn test_pasv_no_port_keeps_session() {
+            let mut session = setup::setup_session_and_login();
+            session.config = Arc::new(ServerConfig {
+                pasv_port_offset: -70000,
+                ..Default::default()
+            });
+
+            let resp = session.exec_cmd(Command::Pasv(vec![])).unwrap();
+            assert!(resp.starts_with("421"), "{resp}");
+            assert!(matches!(session.transfer_mode, TransferMode::NotSpecified));
+            assert!(session.exec_cmd(Command::Noop(vec![])).unwrap().starts_with("200"));
+
+            // an offset taking the port to 0 is out of range too
+            let config = ServerConfig {
+                pasv_port_offset: -50000,
+                ..Default::default()
+            };
+            assert_eq!(config.pasv_advertised_port(50000), None);
+            assert_eq!(config.pasv_advertised_port(50001), Some(1));
+        }
+
+        #[test]
+        fn test_pasv_binds_only_after_login() {
+            let mut session = setup::setup_session_unlogged();
+
+            assert!(session.exec_cmd(Command::Pasv(vec![])).unwrap().starts_with("530"));
+            assert!(session.exec_cmd(Command::Spsv(vec![])).unwrap().starts_with("530"));
+            assert_eq!(session.metrics.active_pasv_listeners(), 0);
+
+            session.exec_cmd(Command::User(vec![USERNAME.into()])).unwrap();
+            session.exec_cmd(Command::Pass(vec![PASSWORD.into()])).unwrap();
+            assert!(session.exec_cmd(Command::Pasv(vec![])).unwrap().starts_with("227"));
+            assert_eq!(session.metrics.active_pasv_listeners(), 1);
+            // a new PASV replaces the listener
+            assert!(session.exec_cmd(Command::Pasv(vec![])).unwrap().starts_with("227"));
+            assert_eq!(session.metrics.active_pasv_listeners(), 1);
+            session.exec_cmd(Command::Noop(vec![])).unwrap();
+            assert_eq!(session.metrics.active_pasv_listeners(), 0);
+        }
+
+        #[test]
+        fn test_non_data_command_abandons_pasv() {
+            let mut session = setup::setup_session_and_login();
+
+            session.exec_cmd(Command::Pasv(vec![])).unwrap();
+            let TransferMode::Pasv(port, _) = session.transfer_mode else {
+                unreachable!()
+            };
+            session.exec_cmd(Command::Type(vec!["I".to_string()])).unwrap();
+            assert!(matches!(session.transfer_mode, TransferMode::Pasv(_, _)));
+
+            assert!(session.exec_cmd(Command::Noop(vec![])).unwrap().starts_with("200"));
+            assert!(matches!(session.transfer_mode, TransferMode::NotSpecified));
+            // the listener is closed
+            assert!(TcpStream::connect(("127.0.0.1", port)).is_err());
+            assert!(session.exec_cmd(Command::List(vec![])).unwrap().starts_with("425"));
+        }
+
+        #[test]
+        fn test_rejected_command_keeps_pasv() {
+            let mut session = setup::setup_session_and_login();
+
+            session.exec_cmd(Command::Pasv(vec![])).unwrap();
+            assert!(session.exec_cmd(Command::Site(vec![])).unwrap().starts_with("501"));
+            assert!(matches!(session.transfer_mode, TransferMode::Pasv(_, _)));
+
+            let _client_conn = utils::data_conn_client(&session);
+            assert!(session.exec_cmd(Command::List(vec![])).unwrap().starts_with("226"));
+        }
+
+        #[test]
+        fn test_disabled_command_keeps_pasv() {
+            let mut session = setup::setup_session_and_login();
+            session.config = Arc::new(ServerConfig {
+                disabled_commands: ["NOOP".to_string()].into(),
+                ..Default::default()
+            });
+
+            session.exec_cmd(Command::Pasv(vec![])).unwrap();
+            assert!(session.exec_cmd(Command::Noop(vec![])).unwrap().starts_with("502"));
+            let _client_conn = utils::data_conn_client(&session);
+            assert!(session.exec_cmd(Command::List(vec![])).unwrap().starts_with("226"));
+        }
+
+        #[test]
+        fn test_pasv_rate_limit() {
+            let mut session = setup::setup_session_and_login();
+            session.config = Arc::new(ServerConfig {
+                max_pasv_per_window: 2,
+                pasv_rate_window: Duration::from_millis(200),
+                ..Default::default()
+            });
+
+            assert!(session.exec_cmd(Command::Pasv(vec![])).unwrap().starts_with("227"));
+            assert!(session.exec_cmd(Command::Pasv(vec![])).unwrap().starts_with("227"));
+            let resp = session.exec_cmd(Command::Pasv(vec![])).unwrap();
+            assert!(resp.starts_with("421"), "{resp}");
+            // a rejected PASV keeps the listener of the previous one
+            assert!(matches!(session.transfer_mode, TransferMode::Pasv(_, _)));
+
+            thread::sleep(Duration::from_millis(200));
+            assert!(session.exec_cmd(Command::Pasv(vec![])).unwrap().starts_with("227"));
+        }
+
+        #[test]
+        fn test_pasv_address() {
+            let mut session = setup::setup_session_and_login();
+
+            let resp = session.exec_cmd(Command::Pasv(vec![])).unwrap();
+            assert!(resp.starts_with("227 (127,0,0,1,"), "{resp}");
+
+            session.config = Arc::new(ServerConfig {
+                pasv_address: Some("1.2.3.4".into()),
+                ..Default::default()
+            });
+            let resp = session.exec_cmd(Command::Pasv(vec![])).unwrap();
+            assert!(resp.starts_with("227 (1,2,3,4,"), "{resp}");
+        }
+
+        #[test]
+        fn test_pasv_bind_address() {
+            let mut session = setup::setup_session_and_login();
+            session.config = Arc::new(ServerConfig {
+                pasv_address: Some("1.2.3.4".into()),
+                pasv_bind_address: Some("127.0.0.1".parse().unwrap()),
+                ..Default::default()
+            });
+
+            let resp = session.exec_cmd(Command::Pasv(vec![])).unwrap();
+            assert!(resp.starts_with("227 (1,2,3,4,"), "{resp}");
+            let TransferMode::Pasv(_, listener) = &session.transfer_mode else {
+                unreachable!()
+            };
+            assert_eq!(listener.local_addr().unwrap().ip().to_string(), "127.0.0.1");
+        }
+
+        #[test]
+        fn test_pasv_address_not_ipv4() {
+            let mut session = setup::setup_session_and_login();
+
+            for address in ["::1", "ftp.example.com", "1.2.3", "1.2.3.4.5"] {
+                session.config = Arc::new(ServerConfig {
+                    pasv_address: Some(address.into()),
+                    ..Default::default()
+                });
+                assert!(session.exec_cmd(Command::Pasv(vec![])).unwrap().starts_with("522"));
+                assert!(matches!(session.transfer_mode, TransferMode::NotSpecified));
+            }
+        }
+
         #[test]
         fn test_pasv_on_pasv() {
-            let (_, mut session) = setup::setup_client_and_session_and_login();
+            let mut session = setup::setup_session_and_login();
 
             session.exec_cmd(Command::Pasv(vec![])).unwrap();
             let old_pasv_port = if let TransferMode::Pasv(port, _) = &session.transfer_mode {
@@ -515,14 +2472,14 @@ mod session_test {
 
         #[test]
         fn test_list_no_mode() {
-            let (_, mut session) = setup::setup_client_and_session_and_login(); 
+            let mut session = setup::setup_session_and_login(); 
 
             assert!(session.exec_cmd(Command::List(vec![".".to_string()])).unwrap().starts_with("425"));
         }
 
         #[test]
         fn test_list_pasv() {
-            let (_, mut session) = setup::setup_client_and_session_and_login(); 
+            let mut session = setup::setup_session_and_login(); 
 
             session.exec_cmd(Command::Pasv(vec![])).unwrap();
             let _ = utils::data_conn_client(&session); // connect to server on pasv port
@@ -530,5 +2487,460 @@ mod session_test {
             
             assert!(matches!(session.transfer_mode, TransferMode::NotSpecified));
         }
+
+        #[test]
+        fn test_list_max_entries() {
+            let mut session = setup::setup_session_and_login();
+            session.config = Arc::new(ServerConfig {
+                max_list_entries: 2,
+                ..Default::default()
+            });
+
+            session.exec_cmd(Command::Pasv(vec![])).unwrap();
+            let mut client_conn = utils::data_conn_client(&session);
+            assert!(session.exec_cmd(Command::List(vec![])).unwrap().starts_with("226"));
+            let mut listing = String::new();
+            std::io::Read::read_to_string(&mut client_conn, &mut listing).unwrap();
+            assert_eq!(listing, ".\r\n..\r\n... listing truncated after 2 of 6 entries\r\n");
+        }
+
+        #[test]
+        fn test_list_follows_type() {
+            let mut session = setup::setup_session_and_login();
+
+            for (type_arg, listing) in [
+                ("A", ".\r\n..\r\nthis\r\noutput\r\nis\r\nfake\r\n"),
+                ("I", ".\n..\nthis\noutput\nis\nfake\n"),
+            ] {
+                session.exec_cmd(Command::Type(vec![type_arg.into()])).unwrap();
+                session.exec_cmd(Command::Pasv(vec![])).unwrap();
+                let mut client_conn = utils::data_conn_client(&session);
+                assert!(session.exec_cmd(Command::List(vec![])).unwrap().starts_with("226"));
+                let mut data = String::new();
+                std::io::Read::read_to_string(&mut client_conn, &mut data).unwrap();
+                assert_eq!(data, listing, "TYPE {type_arg}");
+            }
+        }
+
+        #[test]
+        fn test_rein_resets_type() {
+            let mut session = setup::setup_session_and_login();
+            session.exec_cmd(Command::Type(vec!["I".into()])).unwrap();
+            session.exec_cmd(Command::Site(vec!["UMASK 077".into()])).unwrap();
+            session.exec_cmd(Command::Pasv(vec![])).unwrap();
+
+            assert!(session.exec_cmd(Command::Rein(vec![])).unwrap().starts_with("220"));
+            assert_eq!(session.login_status, LoginStatus::Unloggedin);
+            assert!(matches!(session.transfer_mode, TransferMode::NotSpecified));
+            assert_eq!(session.umask, session.config.umask);
+            assert!(session.exec_cmd(Command::Pasv(vec![])).unwrap().starts_with("530"));
+
+            // the next transfer uses the default type, ASCII
+            session.exec_cmd(Command::User(vec![USERNAME.into()])).unwrap();
+            session.exec_cmd(Command::Pass(vec![PASSWORD.into()])).unwrap();
+            session.exec_cmd(Command::Pasv(vec![])).unwrap();
+            let mut client_conn = utils::data_conn_client(&session);
+            assert!(session.exec_cmd(Command::List(vec![])).unwrap().starts_with("226"));
+            let mut data = String::new();
+            std::io::Read::read_to_string(&mut client_conn, &mut data).unwrap();
+            assert_eq!(data, ".\r\n..\r\nthis\r\noutput\r\nis\r\nfake\r\n");
+        }
+
+        #[test]
+        fn test_list_dos_format() {
+            let mut session = setup::setup_session_and_login();
+            session.exec_cmd(Command::Site(vec!["LISTFMT dos".into()])).unwrap();
+            // 2022-05-31 12:26:40 UTC
+            session.clock = Arc::new(FixedClock(std::time::UNIX_EPOCH + Duration::from_secs(1654000000)));
+
+            session.exec_cmd(Command::Pasv(vec![])).unwrap();
+            let mut client_conn = utils::data_conn_client(&session);
+            assert!(session.exec_cmd(Command::List(vec![])).unwrap().starts_with("226"));
+            let mut data = String::new();
+            std::io::Read::read_to_string(&mut client_conn, &mut data).unwrap();
+            let lines = data.lines().collect::<Vec<_>>();
+            assert_eq!(lines[0], "05-31-22  12:26PM       <DIR>          .");
+            assert_eq!(lines[2], "05-31-22  12:26PM                    0 this");
+            assert_eq!(lines.len(), 6);
+        }
+
+        #[test]
+        fn test_port() {
+            let mut session = setup::setup_session_and_login();
+
+            assert!(session.exec_cmd(Command::Port(vec!["127,0,0,1,4,1".to_string()])).unwrap().starts_with("200"));
+            assert!(matches!(session.transfer_mode, TransferMode::Port(addr) if addr == "127.0.0.1:1025".parse().unwrap()));
+        }
+
+        #[test]
+        fn test_port_invalid() {
+            let mut session = setup::setup_session_and_login();
+
+            for arg in ["127,0,0,1,4", "127,0,0,1,4,256", "a,b,c,d,e,f", "127.0.0.1:1025"] {
+                assert!(session.exec_cmd(Command::Port(vec![arg.to_string()])).unwrap().starts_with("501"));
+                assert!(matches!(session.transfer_mode, TransferMode::NotSpecified));
+            }
+        }
+
+        #[test]
+        fn test_port_foreign_address() {
+            let mut session = setup::setup_session_and_login();
+
+            assert!(session.exec_cmd(Command::Port(vec!["10,0,0,1,4,1".to_string()])).unwrap().starts_with("501"));
+            assert!(matches!(session.transfer_mode, TransferMode::NotSpecified));
+
+            session.config = Arc::new(ServerConfig {
+                allow_foreign_port_address: true,
+                ..Default::default()
+            });
+            assert!(session.exec_cmd(Command::Port(vec!["10,0,0,1,4,1".to_string()])).unwrap().starts_with("200"));
+        }
+
+        #[test]
+        fn test_list_port() {
+            let mut session = setup::setup_session_and_login();
+
+            let data_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let port = data_listener.local_addr().unwrap().port();
+            let accept_thread = thread::spawn(move || utils::accept_in_time(&data_listener).0);
+
+            let (p1, p2) = encode_pasv_port(port);
+            session.exec_cmd(Command::Port(vec![format!("127,0,0,1,{p1:},{p2:}")])).unwrap();
+            assert!(session.exec_cmd(Command::List(vec![])).unwrap().starts_with("226"));
+            assert!(matches!(session.transfer_mode, TransferMode::NotSpecified));
+
+            let mut listing = String::new();
+            BufReader::new(accept_thread.join().unwrap()).read_line(&mut listing).unwrap();
+            assert_string_trim_eq(listing, ".");
+        }
+
+        #[test]
+        fn test_list_port_from_source_port() {
+            let source_port = portpicker::pick_unused_port().unwrap();
+            // a port that can't be bound falls back to an ephemeral one
+            let taken = TcpListener::bind("0.0.0.0:0").unwrap();
+            for (source_port, expected) in [(Some(source_port), Some(source_port)), (Some(taken.local_addr().unwrap().port()), None)] {
+                let mut session = setup::setup_session_and_login();
+                session.config = Arc::new(ServerConfig {
+                    active_data_source_port: source_port,
+                    ..Default::default()
+                });
+
+                let data_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+                let (p1, p2) = encode_pasv_port(data_listener.local_addr().unwrap().port());
+                let accept_thread = thread::spawn(move || utils::accept_in_time(&data_listener).1);
+                session.exec_cmd(Command::Port(vec![format!("127,0,0,1,{p1:},{p2:}")])).unwrap();
+                assert!(session.exec_cmd(Command::List(vec![])).unwrap().starts_with("226"));
+
+                let peer = accept_thread.join().unwrap();
+                match expected {
+                    Some(port) => assert_eq!(peer.port(), port),
+                    None => assert_ne!(Some(peer.port()), source_port),
+                }
+            }
+        }
+
+        #[test]
+        fn test_list_port_refused() {
+            let mut session = setup::setup_session_and_login();
+
+            // nothing listens on the port once the listener is dropped
+            let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+            let (p1, p2) = encode_pasv_port(port);
+            session.exec_cmd(Command::Port(vec![format!("127,0,0,1,{p1:},{p2:}")])).unwrap();
+            let resp = session.exec_cmd(Command::List(vec![])).unwrap();
+            assert_eq!(resp, "425 Can't open data connection: connection refused.\r\n");
+        }
+
+        #[test]
+        fn test_empty_transfer() {
+            let (mut client, mut session) = setup::setup_client_and_session_and_login();
+
+            session.exec_cmd(Command::Pasv(vec![])).unwrap();
+            let mut client_conn = utils::data_conn_client(&session);
+            let (resp, transfer) = session.data_connection_wrapper("empty", Direction::Outgoing, |_| Ok(TransferResult::default())).unwrap();
+            assert_eq!(client.get_msg_code().unwrap(), 150);
+            assert!(resp.starts_with("226"));
+            assert_eq!(transfer, Some(TransferResult::default()));
+
+            // data connection is closed with nothing sent
+            let mut data = Vec::new();
+            client_conn.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+            assert_eq!(std::io::Read::read_to_end(&mut client_conn, &mut data).unwrap(), 0);
+        }
+
+        #[test]
+        fn test_all_data_arrives_before_226() {
+            let (mut client, mut session) = setup::setup_client_and_session_and_login();
+            let payload = (0..1 << 20).map(|i| i as u8).collect::<Vec<_>>();
+
+            session.exec_cmd(Command::Pasv(vec![])).unwrap();
+            let mut client_conn = utils::data_conn_client(&session);
+            // larger than socket buffers, so it's read while being sent
+            let reader = thread::spawn(move || {
+                let mut data = Vec::new();
+                std::io::Read::read_to_end(&mut client_conn, &mut data).unwrap();
+                data
+            });
+            let (resp, _) = session
+                .data_connection_wrapper("large", Direction::Outgoing, |stream| {
+                    stream.write_all(&payload)?;
+                    Ok(TransferResult { bytes: payload.len() })
+                })
+                .unwrap();
+            assert_eq!(client.get_msg_code().unwrap(), 150);
+            assert!(resp.starts_with("226"));
+            // the data connection is closed once 226 can be sent
+            assert_eq!(reader.join().unwrap(), payload);
+        }
+
+        #[test]
+        fn test_transfer_logic_mutates_state() {
+            let (mut client, mut session) = setup::setup_client_and_session_and_login();
+
+            session.exec_cmd(Command::Pasv(vec![])).unwrap();
+            let mut client_conn = utils::data_conn_client(&session);
+            let mut written = Vec::new();
+            let (resp, transfer) = session
+                .data_connection_wrapper("counted", Direction::Outgoing, |stream| {
+                    for chunk in ["ab", "cde"] {
+                        stream.write_all(chunk.as_bytes())?;
+                        written.push(chunk);
+                    }
+                    Ok(TransferResult { bytes: 5 })
+                })
+                .unwrap();
+            assert_eq!(client.get_msg_code().unwrap(), 150);
+            assert!(resp.starts_with("226"));
+            assert_eq!(written, ["ab", "cde"]);
+            assert_eq!(transfer, Some(TransferResult { bytes: 5 }));
+
+            let mut data = String::new();
+            std::io::Read::read_to_string(&mut client_conn, &mut data).unwrap();
+            assert_eq!(data, "abcde");
+        }
+
+        #[test]
+        fn test_max_transfers_per_user() {
+            let mut session = setup::setup_session_and_login();
+            session.transfer_limit = Arc::new(TransferLimit::new(1));
+
+            // another connection of the same anonymous client is running a transfer
+            let running = session.transfer_limit.acquire(&session.client_ip().to_string()).unwrap();
+            session.exec_cmd(Command::Pasv(vec![])).unwrap();
+            let resp = session.exec_cmd(Command::List(vec![])).unwrap();
+            assert_string_trim_eq(resp, "425 Too many data connections.");
+            assert!(matches!(session.transfer_mode, TransferMode::NotSpecified));
+
+            drop(running);
+            session.exec_cmd(Command::Pasv(vec![])).unwrap();
+            let _client_conn = utils::data_conn_client(&session);
+            assert!(session.exec_cmd(Command::List(vec![])).unwrap().starts_with("226"));
+            // the slot is released after the transfer
+            assert!(session.transfer_limit.acquire(&session.client_ip().to_string()).is_some());
+        }
+
+        #[test]
+        fn test_transfer_progress() {
+            let mut session = setup::setup_session_and_login();
+
+            session.exec_cmd(Command::Pasv(vec![])).unwrap();
+            let _client_conn = utils::data_conn_client(&session);
+            let progress = session.transfer_progress.clone();
+            session
+                .data_connection_wrapper("progress", Direction::Outgoing, |stream| {
+                    stream.write_all(b"abc")?;
+                    assert_eq!(progress.load(Ordering::Relaxed), 3);
+                    Ok(TransferResult::default())
+                })
+                .unwrap();
+            assert!(!session.exec_cmd(Command::Stat(vec![])).unwrap().contains("Transferring"));
+        }
+
+        #[test]
+        fn test_stat_during_transfer() {
+            let (mut client, mut session) = setup::setup_client_and_session_and_login();
+            session.config = Arc::new(ServerConfig {
+                stat_during_transfer_after: Some(Duration::ZERO),
+                ..Default::default()
+            });
+            session.exec_cmd(Command::Pasv(vec![])).unwrap();
+            let _client_conn = utils::data_conn_client(&session);
+
+            // the client asks for STAT in the middle of a slow transfer, then sends a command that has to wait
+            let answered = Arc::new(std::sync::atomic::AtomicBool::new(false));
+            let client_thread = {
+                let answered = answered.clone();
+                thread::spawn(move || {
+                    client.expect_response("transfer start", 150).unwrap();
+                    client.send_msg_add_crlf("STAT").unwrap();
+                    let (code, lines) = client.get_full_response().unwrap();
+                    client.send_msg_add_crlf("NOOP").unwrap();
+                    answered.store(true, Ordering::Relaxed);
+                    (code, lines)
+                })
+            };
+            let (resp, _) = session
+                .data_connection_wrapper("slow file", Direction::Outgoing, |stream| {
+                    for _ in 0..500 {
+                        if answered.load(Ordering::Relaxed) {
+                            break;
+                        }
+                        stream.write_all(b"x")?;
+                        thread::sleep(Duration::from_millis(10));
+                    }
+                    Ok(TransferResult::default())
+                })
+                .unwrap();
+            assert!(resp.starts_with("226"), "{resp}");
+
+            let (code, lines) = client_thread.join().unwrap();
+            assert_eq!(code, 211);
+            assert!(lines.iter().any(|line| line.starts_with("Transferring slow file: ") && line.ends_with(" bytes so far")), "{lines:?}");
+            // NOOP was left for after the transfer
+            assert!(matches!(session.get_cmd().unwrap().unwrap(), Command::Noop(_)));
+        }
+
+        #[test]
+        fn test_disabled_stat_during_transfer() {
+            let (mut client, mut session) = setup::setup_client_and_session_and_login();
+            session.config = Arc::new(ServerConfig {
+                stat_during_transfer_after: Some(Duration::ZERO),
+                disabled_commands: ["STAT".to_string()].into(),
+                ..Default::default()
+            });
+            session.exec_cmd(Command::Pasv(vec![])).unwrap();
+            let _client_conn = utils::data_conn_client(&session);
+
+            let answered = Arc::new(std::sync::atomic::AtomicBool::new(false));
+            let client_thread = {
+                let answered = answered.clone();
+                thread::spawn(move || {
+                    client.expect_response("transfer start", 150).unwrap();
+                    client.send_msg_add_crlf("STAT").unwrap();
+                    let code = client.get_msg_code().unwrap();
+                    answered.store(true, Ordering::Relaxed);
+                    code
+                })
+            };
+            let (resp, _) = session
+                .data_connection_wrapper("slow file", Direction::Outgoing, |stream| {
+                    for _ in 0..500 {
+                        if answered.load(Ordering::Relaxed) {
+                            break;
+                        }
+                        stream.write_all(b"x")?;
+                        thread::sleep(Duration::from_millis(10));
+                    }
+                    Ok(TransferResult::default())
+                })
+                .unwrap();
+            assert!(resp.starts_with("226"), "{resp}");
+            assert_eq!(client_thread.join().unwrap(), 502);
+        }
+
+        #[test]
+        #[cfg(target_os = "linux")]
+        fn test_data_buffer_sizes() {
+            let mut session = setup::setup_session_and_login();
+            session.config = Arc::new(ServerConfig {
+                data_recv_buffer_size: Some(262144),
+                data_send_buffer_size: Some(262144),
+                ..Default::default()
+            });
+
+            session.exec_cmd(Command::Pasv(vec![])).unwrap();
+            let (_client_conn, server_conn) = utils::data_conn_client_server(&session);
+            session.apply_buffer_sizes(&server_conn);
+            // linux reports twice the requested size for bookkeeping overhead
+            assert!(socket_opts::buffer_size(&server_conn, Buffer::Receive).unwrap() >= 262144);
+            assert!(socket_opts::buffer_size(&server_conn, Buffer::Send).unwrap() >= 262144);
+        }
+
+        #[test]
+        fn test_list_access_log() {
+            let mut session = setup::setup_session_and_login();
+            let path = std::env::temp_dir().join(format!("rust-ftp-session-access-log-{}", std::process::id()));
+            let _ = std::fs::remove_file(&path);
+            session.config = Arc::new(ServerConfig {
+                access_log_path: Some(path.clone()),
+                ..Default::default()
+            });
+
+            session.exec_cmd(Command::Pasv(vec![])).unwrap();
+            let _ = utils::data_conn_client(&session);
+            session.clock = Arc::new(FixedClock(std::time::UNIX_EPOCH + Duration::from_secs(1654000000)));
+            session.exec_cmd(Command::List(vec!["/pub".to_string()])).unwrap();
+
+            let content = std::fs::read_to_string(&path).unwrap();
+            assert_eq!(content, "1654000000 127.0.0.1 31 /pub o c\n");
+            std::fs::remove_file(&path).unwrap();
+        }
+
+        #[test]
+        fn test_list_pasv_command_timeout() {
+            let mut session = setup::setup_session_and_login();
+            session.config = Arc::new(ServerConfig {
+                command_timeout: Some(Duration::from_millis(100)),
+                ..Default::default()
+            });
+
+            // nobody connects to the passive port
+            session.exec_cmd(Command::Pasv(vec![])).unwrap();
+            let start = Instant::now();
+            assert!(session.exec_cmd(Command::List(vec![])).unwrap().starts_with("425"));
+            assert!(start.elapsed() < Duration::from_secs(1));
+
+            // connecting in time still works
+            session.exec_cmd(Command::Pasv(vec![])).unwrap();
+            let _ = utils::data_conn_client(&session);
+            assert!(session.exec_cmd(Command::List(vec![])).unwrap().starts_with("226"));
+        }
+
+        #[test]
+        fn test_dropped_data_connection() {
+            let mut session = setup::setup_session_and_login();
+            for kind in [ErrorKind::BrokenPipe, ErrorKind::ConnectionReset] {
+                session.exec_cmd(Command::Pasv(vec![])).unwrap();
+                let _ = utils::data_conn_client(&session);
+                let (resp, transfer) = session
+                    .data_connection_wrapper(".", Direction::Outgoing, |_| Err(io::Error::from(kind).into()))
+                    .unwrap();
+                assert!(resp.starts_with("426"), "{resp}");
+                assert!(transfer.is_none());
+                assert!(session.transferring.is_none());
+            }
+            // the control session is still usable
+            assert!(session.exec_cmd(Command::Noop(vec![])).unwrap().starts_with("200"));
+        }
+
+        #[test]
+        fn test_list_pasv_matching_data_ip() {
+            let mut session = setup::setup_session_and_login();
+            session.config = Arc::new(ServerConfig {
+                require_matching_data_ip: true,
+                ..Default::default()
+            });
+
+            session.exec_cmd(Command::Pasv(vec![])).unwrap();
+            let _ = utils::data_conn_client(&session);
+            assert!(session.exec_cmd(Command::List(vec![])).unwrap().starts_with("226"));
+        }
+
+        #[test]
+        fn test_list_pasv_mismatching_data_ip() {
+            let mut session = setup::setup_session_and_login();
+            session.config = Arc::new(ServerConfig {
+                require_matching_data_ip: true,
+                ..Default::default()
+            });
+            // pretend the control connection comes from another host
+            session.client_addr = "10.0.0.1:12345".parse().unwrap();
+
+            session.exec_cmd(Command::Pasv(vec![])).unwrap();
+            let _ = utils::data_conn_client(&session);
+            assert!(session.exec_cmd(Command::List(vec![])).unwrap().starts_with("425"));
+            assert!(matches!(session.transfer_mode, TransferMode::NotSpecified));
+        }
     }
 }
@@ -0,0 +1,201 @@
+//! # site_exec
+//! This module contains `run`, which runs a program for `SITE EXEC` with bounds on what it can cost the server:
+//!
+//! - the program must be an absolute path and gets an empty environment, so nothing depends on the server's `PATH`
+//! - the program is killed if it doesn't exit within the timeout, on unix with every process it started
+//!   (it runs in its own process group)
+//! - at most `max_output` bytes of stdout and stderr are kept, the pipes are closed after that,
+//!   so a program printing without end gets EPIPE instead of being buffered
+//! - output is only waited for until the timeout, a process left running that still holds the pipes
+//!   (e.g. started in the background) doesn't hold up the reply
+
+use std::{
+    io::{self, Read},
+    path::Path,
+    process::{Child, Command, ExitStatus, Stdio},
+    sync::mpsc::{self, Receiver},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// how often the program is checked for exit while waiting for it
+const WAIT_INTERVAL: Duration = Duration::from_millis(10);
+/// how long output is waited for after the timeout, for the pipes of a killed program to be closed
+const OUTPUT_GRACE: Duration = Duration::from_millis(100);
+
+/// What a program run by `run` did
+#[derive(Debug)]
+pub struct ExecOutput {
+    /// None if the program was killed because it ran out of time
+    pub status: Option<ExitStatus>,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    /// the program printed more than `max_output` bytes on stdout or stderr,
+    ///     or its output wasn't complete by the timeout
+    pub truncated: bool,
+}
+
+/// Run argv[0] (an absolute path) with the rest of argv as arguments and wait at most `timeout` for it
+pub fn run(argv: &[&str], timeout: Duration, max_output: usize) -> io::Result<ExecOutput> {
+    let (program, args) = argv
+        .split_first()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no program"))?;
+    if !Path::new(program).is_absolute() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{program:} is not an absolute path"),
+        ));
+    }
+    let mut command = Command::new(program);
+    command
+        .args(args)
+        .env_clear()
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    #[cfg(unix)]
+    std::os::unix::process::CommandExt::process_group(&mut command, 0);
+    let mut child = command.spawn()?;
+    let stdout = read_at_most(child.stdout.take(), max_output);
+    let stderr = read_at_most(child.stderr.take(), max_output);
+
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break Some(status);
+        }
+        if Instant::now() >= deadline {
+            kill(&mut child);
+            child.wait()?;
+            break None;
+        }
+        thread::sleep(WAIT_INTERVAL);
+    };
+    let output_deadline = deadline.max(Instant::now()) + OUTPUT_GRACE;
+    let (stdout, stdout_truncated) = receive_before(&stdout, output_deadline);
+    let (stderr, stderr_truncated) = receive_before(&stderr, output_deadline);
+    Ok(ExecOutput {
+        status,
+        stdout,
+        stderr,
+        truncated: stdout_truncated || stderr_truncated,
+    })
+}
+
+/// kill child, on unix its whole process group so nothing it started keeps running with the pipes
+fn kill(child: &mut Child) {
+    #[cfg(unix)]
+    if let Ok(pid) = libc::pid_t::try_from(child.id()) {
+        // SAFETY: plain syscall. The group is child's own (see `process_group`) and exists until child is
+        //     reaped, so only processes of this program are signaled
+        unsafe { libc::kill(-pid, libc::SIGKILL) };
+        return;
+    }
+    // it may have exited meanwhile, then kill fails and wait reaps it
+    let _ = child.kill();
+}
+
+/// read at most `max` bytes of pipe in a thread, whether there was more is sent with the bytes
+fn read_at_most<R: Read + Send + 'static>(
+    pipe: Option<R>,
+    max: usize,
+) -> Receiver<(Vec<u8>, bool)> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut buf = Vec::new();
+        if let Some(pipe) = pipe {
+            // one byte more than max tells whether there's more, the pipe is closed when it's dropped
+            let _ = pipe.take(max as u64 + 1).read_to_end(&mut buf);
+        }
+        let truncated = buf.len() > max;
+        buf.truncate(max);
+        let _ = sender.send((buf, truncated));
+    });
+    receiver
+}
+
+/// the output read by `read_at_most` if it's done by deadline, else nothing and truncated.
+///     A reader that isn't done is left to finish by itself when the pipe is closed
+fn receive_before(output: &Receiver<(Vec<u8>, bool)>, deadline: Instant) -> (Vec<u8>, bool) {
+    output
+        .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+        .unwrap_or((Vec::new(), true))
+}
+
+#[cfg(test)]
+mod site_exec_test {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    #[test]
+    fn test_run() {
+        let output = run(&["/bin/echo", "hello"], TIMEOUT, 1024).unwrap();
+        assert!(output.status.unwrap().success());
+        assert_eq!(output.stdout, b"hello\n");
+        assert!(!output.truncated);
+
+        let output = run(&["/bin/sh", "-c", "echo oops >&2; exit 3"], TIMEOUT, 1024).unwrap();
+        assert_eq!(output.status.unwrap().code(), Some(3));
+        assert_eq!(output.stderr, b"oops\n");
+    }
+
+    #[test]
+    fn test_run_relative_program() {
+        for argv in [&["echo", "hello"][..], &["./echo"], &[]] {
+            let err = run(argv, TIMEOUT, 1024).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput, "{argv:?}");
+        }
+    }
+
+    #[test]
+    fn test_run_empty_environment() {
+        let output = run(&["/bin/sh", "-c", "echo \"[$HOME]\""], TIMEOUT, 1024).unwrap();
+        assert_eq!(output.stdout, b"[]\n");
+    }
+
+    #[test]
+    fn test_run_timeout() {
+        let start = Instant::now();
+        let output = run(&["/bin/sleep", "10"], Duration::from_millis(100), 1024).unwrap();
+        assert!(output.status.is_none());
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_run_timeout_kills_children() {
+        let start = Instant::now();
+        // the shell's child inherits the pipes and would keep them open for 10s
+        let output = run(
+            &["/bin/sh", "-c", "/bin/sleep 10; echo done"],
+            Duration::from_millis(100),
+            1024,
+        )
+        .unwrap();
+        assert!(output.status.is_none());
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_run_background_process_holding_output() {
+        let start = Instant::now();
+        let output = run(
+            &["/bin/sh", "-c", "echo started; /bin/sleep 10 &"],
+            Duration::from_millis(200),
+            1024,
+        )
+        .unwrap();
+        assert!(output.status.unwrap().success());
+        assert!(output.truncated);
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_run_output_limit() {
+        // yes never stops by itself, it exits on EPIPE once the output is cut
+        let output = run(&["/usr/bin/yes"], TIMEOUT, 10).unwrap();
+        assert!(output.status.is_some());
+        assert_eq!(output.stdout, b"y\ny\ny\ny\ny\n");
+        assert!(output.truncated);
+    }
+}
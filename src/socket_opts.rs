@@ -0,0 +1,254 @@
+//! # socket_opts
+//! This module contains socket operations that std doesn't expose: kernel buffer sizes, and binding
+//! a local port (with `SO_REUSEADDR`) before connecting.
+//!
+//! On unix they're done with libc directly. A crate like socket2 would wrap the same syscalls,
+//! libc is used since it's already in the dependency tree (env_logger and portpicker depend on it),
+//! which keeps the build to the crates it already has. Elsewhere there's a fallback that keeps the
+//! OS defaults, see `fallback`
+
+use std::{io, net::SocketAddrV4, net::TcpStream, time::Duration};
+
+#[cfg(unix)]
+pub use self::unix::{bind_tcp_socket, buffer_size, connect_socket, set_buffer_size};
+
+#[cfg(not(unix))]
+pub use self::fallback::{bind_tcp_socket, buffer_size, connect_socket, set_buffer_size};
+
+/// Which kernel buffer of a socket
+#[derive(Debug, Clone, Copy)]
+pub enum Buffer {
+    /// `SO_RCVBUF`
+    Receive,
+    /// `SO_SNDBUF`
+    Send,
+}
+
+#[cfg(unix)]
+mod unix {
+    use super::*;
+    use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+
+    /// A socket bound by `bind_tcp_socket`, not connected yet
+    pub type BoundSocket = OwnedFd;
+
+    impl Buffer {
+        fn option_name(self) -> libc::c_int {
+            match self {
+                Buffer::Receive => libc::SO_RCVBUF,
+                Buffer::Send => libc::SO_SNDBUF,
+            }
+        }
+    }
+
+    /// request the kernel buffer of stream to be `size` bytes, the kernel may round or double it
+    pub fn set_buffer_size(stream: &TcpStream, buffer: Buffer, size: usize) -> io::Result<()> {
+        let size = libc::c_int::try_from(size)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "buffer size too large"))?;
+        // SAFETY: fd is a valid socket owned by stream, and the option value is a c_int of the given length
+        let ret = unsafe {
+            libc::setsockopt(
+                stream.as_raw_fd(),
+                libc::SOL_SOCKET,
+                buffer.option_name(),
+                &size as *const libc::c_int as *const libc::c_void,
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// the size of the kernel buffer of stream in bytes
+    pub fn buffer_size(stream: &TcpStream, buffer: Buffer) -> io::Result<usize> {
+        let mut size: libc::c_int = 0;
+        let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+        // SAFETY: fd is a valid socket owned by stream, size and len are valid for writes of their types
+        let ret = unsafe {
+            libc::getsockopt(
+                stream.as_raw_fd(),
+                libc::SOL_SOCKET,
+                buffer.option_name(),
+                &mut size as *mut libc::c_int as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(size as usize)
+    }
+
+    fn to_sockaddr_in(addr: SocketAddrV4) -> libc::sockaddr_in {
+        // SAFETY: sockaddr_in is plain data, all zeroes is a valid value.
+        // Its fields differ between unix flavors, so only the common ones are set below
+        let mut sockaddr: libc::sockaddr_in = unsafe { std::mem::zeroed() };
+        #[cfg(any(
+            target_os = "macos",
+            target_os = "ios",
+            target_os = "freebsd",
+            target_os = "openbsd",
+            target_os = "netbsd",
+            target_os = "dragonfly"
+        ))]
+        {
+            sockaddr.sin_len = std::mem::size_of::<libc::sockaddr_in>() as u8;
+        }
+        sockaddr.sin_family = libc::AF_INET as libc::sa_family_t;
+        sockaddr.sin_port = addr.port().to_be();
+        sockaddr.sin_addr = libc::in_addr {
+            s_addr: u32::from(*addr.ip()).to_be(),
+        };
+        sockaddr
+    }
+
+    /// create a TCP socket bound to local, with `SO_REUSEADDR` so that the port can be bound again
+    ///     while connections from it are in TIME_WAIT
+    pub fn bind_tcp_socket(local: SocketAddrV4) -> io::Result<BoundSocket> {
+        // SAFETY: plain syscall, the returned fd is owned right away so it's closed on every error path
+        let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_STREAM, 0) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: fd is a newly created socket nobody else owns
+        let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+        // SOCK_CLOEXEC isn't available on every unix (e.g. macOS), so the flag is set right after creation
+        // SAFETY: fd is a valid socket, F_SETFD takes an int argument
+        if unsafe { libc::fcntl(socket.as_raw_fd(), libc::F_SETFD, libc::FD_CLOEXEC) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let reuse: libc::c_int = 1;
+        let addr = to_sockaddr_in(local);
+        // SAFETY: fd is a valid socket, the option value is a c_int of the given length
+        let ret = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_REUSEADDR,
+                &reuse as *const libc::c_int as *const libc::c_void,
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: fd is a valid socket, the address is of the given length
+        let ret = unsafe {
+            libc::bind(
+                socket.as_raw_fd(),
+                &addr as *const libc::sockaddr_in as *const libc::sockaddr,
+                std::mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+            )
+        };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(socket)
+    }
+
+    /// connect a socket created by `bind_tcp_socket` to remote, giving up after timeout if it's set
+    pub fn connect_socket(
+        socket: BoundSocket,
+        remote: SocketAddrV4,
+        timeout: Option<Duration>,
+    ) -> io::Result<TcpStream> {
+        let stream = TcpStream::from(socket);
+        // connect of a blocking socket honors the send timeout
+        stream.set_write_timeout(timeout)?;
+        let addr = to_sockaddr_in(remote);
+        // SAFETY: fd is a valid socket owned by stream, the address is of the given length
+        let ret = unsafe {
+            libc::connect(
+                stream.as_raw_fd(),
+                &addr as *const libc::sockaddr_in as *const libc::sockaddr,
+                std::mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+            )
+        };
+        if ret != 0 {
+            let e = io::Error::last_os_error();
+            // a timed out connect reports EINPROGRESS
+            if e.raw_os_error() == Some(libc::EINPROGRESS) {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "connect timed out"));
+            }
+            return Err(e);
+        }
+        stream.set_write_timeout(None)?;
+        Ok(stream)
+    }
+}
+
+/// The operations where libc isn't used, they keep the OS defaults
+#[cfg(not(unix))]
+mod fallback {
+    use super::*;
+
+    /// Binding before connecting isn't supported, so there's never a bound socket
+    pub enum BoundSocket {}
+
+    fn unsupported() -> io::Error {
+        io::Error::new(io::ErrorKind::Unsupported, "not supported on this platform")
+    }
+
+    /// does nothing, the OS default buffer size is kept
+    pub fn set_buffer_size(_stream: &TcpStream, _buffer: Buffer, _size: usize) -> io::Result<()> {
+        Ok(())
+    }
+
+    pub fn buffer_size(_stream: &TcpStream, _buffer: Buffer) -> io::Result<usize> {
+        Err(unsupported())
+    }
+
+    /// always fails, so active data connections are made from an ephemeral port
+    pub fn bind_tcp_socket(_local: SocketAddrV4) -> io::Result<BoundSocket> {
+        Err(unsupported())
+    }
+
+    pub fn connect_socket(
+        socket: BoundSocket,
+        _remote: SocketAddrV4,
+        _timeout: Option<Duration>,
+    ) -> io::Result<TcpStream> {
+        match socket {}
+    }
+}
+
+#[cfg(all(test, unix))]
+mod socket_opts_test {
+    use super::*;
+    use std::net::{Ipv4Addr, TcpListener};
+
+    #[test]
+    fn test_set_buffer_size() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+
+        for buffer in [Buffer::Receive, Buffer::Send] {
+            set_buffer_size(&stream, buffer, 4096).unwrap();
+            let small = buffer_size(&stream, buffer).unwrap();
+            set_buffer_size(&stream, buffer, 262144).unwrap();
+            let large = buffer_size(&stream, buffer).unwrap();
+            assert!(small < large, "{buffer:?}: {small} >= {large}");
+        }
+    }
+
+    #[test]
+    fn test_connect_from_bound_port() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let std::net::SocketAddr::V4(remote) = listener.local_addr().unwrap() else {
+            unreachable!()
+        };
+        let local_port = portpicker::pick_unused_port().unwrap();
+
+        for _ in 0..2 {
+            // the port can be bound again right after a connection from it is closed
+            let socket =
+                bind_tcp_socket(SocketAddrV4::new(Ipv4Addr::LOCALHOST, local_port)).unwrap();
+            let stream = connect_socket(socket, remote, Some(Duration::from_secs(1))).unwrap();
+            let (_, peer) = listener.accept().unwrap();
+            assert_eq!(peer.port(), local_port);
+            assert_eq!(stream.local_addr().unwrap().port(), local_port);
+        }
+    }
+}
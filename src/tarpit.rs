@@ -0,0 +1,74 @@
+//! # tarpit
+//! This module contains `Tarpit`, a table of ips that recently failed to login, shared by all sessions
+//! so that connections from these ips can be slowed down
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+pub struct Tarpit {
+    /// when each ip last failed to login
+    failures: Mutex<HashMap<IpAddr, Instant>>,
+    /// a failure is forgotten after this long
+    ttl: Duration,
+}
+
+impl Tarpit {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            failures: Mutex::new(HashMap::new()),
+            ttl,
+        }
+    }
+
+    /// remember that ip failed to login just now, expired failures are dropped to bound the table
+    pub fn record_failure(&self, ip: IpAddr) {
+        let now = Instant::now();
+        let mut failures = self.failures.lock().unwrap();
+        failures.retain(|_, failed_at| now.duration_since(*failed_at) < self.ttl);
+        failures.insert(ip, now);
+    }
+
+    /// whether ip failed to login within ttl
+    pub fn is_recent_offender(&self, ip: IpAddr) -> bool {
+        self.failures
+            .lock()
+            .unwrap()
+            .get(&ip)
+            .is_some_and(|failed_at| failed_at.elapsed() < self.ttl)
+    }
+}
+
+#[cfg(test)]
+mod tarpit_test {
+    use super::*;
+    use std::thread::sleep;
+
+    #[test]
+    fn test_record_failure() {
+        let tarpit = Tarpit::new(Duration::from_secs(60));
+        let ip = "10.0.0.1".parse().unwrap();
+
+        assert!(!tarpit.is_recent_offender(ip));
+        tarpit.record_failure(ip);
+        assert!(tarpit.is_recent_offender(ip));
+        assert!(!tarpit.is_recent_offender("10.0.0.2".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_failure_expires() {
+        let tarpit = Tarpit::new(Duration::from_millis(50));
+        let ip = "10.0.0.1".parse().unwrap();
+
+        tarpit.record_failure(ip);
+        sleep(Duration::from_millis(100));
+        assert!(!tarpit.is_recent_offender(ip));
+
+        // expired failures are dropped on next record
+        tarpit.record_failure("10.0.0.2".parse().unwrap());
+        assert_eq!(tarpit.failures.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,81 @@
+//! # telnet
+//! The control connection is a TELNET channel (RFC 959), this module strips TELNET commands from it
+//! so that e.g. the `IAC IP IAC DM` sent before ABOR doesn't corrupt command parsing.
+//! Option negotiation is not answered, options are simply ignored.
+
+const IAC: u8 = 255;
+const DONT: u8 = 254;
+const WILL: u8 = 251;
+const SB: u8 = 250;
+const SE: u8 = 240;
+
+/// Returns line with all TELNET commands removed, `IAC IAC` is kept as a single 255 data byte
+pub fn strip_telnet_commands(line: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(line.len());
+    let mut bytes = line.iter().copied();
+    while let Some(byte) = bytes.next() {
+        if byte != IAC {
+            data.push(byte);
+            continue;
+        }
+        match bytes.next() {
+            Some(IAC) => data.push(IAC),
+            // WILL/WONT/DO/DONT are followed by an option code
+            Some(WILL..=DONT) => {
+                bytes.next();
+            }
+            // subnegotiation lasts until IAC SE
+            Some(SB) => {
+                let mut last = 0;
+                for byte in bytes.by_ref() {
+                    if last == IAC && byte == SE {
+                        break;
+                    }
+                    last = byte;
+                }
+            }
+            // other commands like IP, DM are two bytes
+            _ => {}
+        }
+    }
+    data
+}
+
+#[cfg(test)]
+mod telnet_test {
+    use super::*;
+
+    #[test]
+    fn test_no_telnet_commands() {
+        assert_eq!(strip_telnet_commands(b"QUIT\r\n"), b"QUIT\r\n");
+        assert_eq!(strip_telnet_commands(b""), b"");
+    }
+
+    #[test]
+    fn test_strip_interrupt_before_abor() {
+        // IAC IP IAC DM ABOR
+        assert_eq!(strip_telnet_commands(b"\xff\xf4\xff\xf2ABOR\r\n"), b"ABOR\r\n");
+    }
+
+    #[test]
+    fn test_strip_option_negotiation() {
+        // IAC WILL ECHO, IAC DONT SUPPRESS-GO-AHEAD
+        assert_eq!(strip_telnet_commands(b"\xff\xfb\x01NOOP\xff\xfe\x03\r\n"), b"NOOP\r\n");
+    }
+
+    #[test]
+    fn test_strip_subnegotiation() {
+        assert_eq!(strip_telnet_commands(b"\xff\xfa\x18\x00xterm\xff\xf0USER a\r\n"), b"USER a\r\n");
+    }
+
+    #[test]
+    fn test_escaped_iac() {
+        assert_eq!(strip_telnet_commands(b"a\xff\xffb"), b"a\xffb");
+    }
+
+    #[test]
+    fn test_truncated_command() {
+        assert_eq!(strip_telnet_commands(b"QUIT\xff"), b"QUIT");
+        assert_eq!(strip_telnet_commands(b"QUIT\xff\xfb"), b"QUIT");
+    }
+}
@@ -0,0 +1,168 @@
+//! # test_client
+//! This module contains `TestClient`, a minimal client of the control connection,
+//! used by tests and by the `--self-test` mode
+
+use crate::host_port::decode_pasv_port;
+use anyhow::{anyhow, Result};
+use std::{
+    io::{BufRead, BufReader, BufWriter, Write},
+    net::{TcpStream, ToSocketAddrs},
+};
+
+pub struct TestClient {
+    pub(crate) cmd_reader: BufReader<TcpStream>,
+    pub(crate) cmd_writer: BufWriter<TcpStream>,
+}
+
+impl TestClient {
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+        let client = TcpStream::connect(addr)?;
+        Ok(TestClient {
+            cmd_reader: BufReader::new(client.try_clone()?),
+            cmd_writer: BufWriter::new(client),
+        })
+    }
+
+    /// receive one line message from server and trim it
+    pub fn get_msg_trimed(&mut self) -> Result<String> {
+        let mut line = String::new();
+        let bytes = self.cmd_reader.read_line(&mut line)?;
+        if bytes == 0 {
+            return Err(anyhow!("connection closed"));
+        }
+        Ok(line.trim().to_string())
+    }
+
+    /// receive a whole response and return its code, multi-line responses are read to their last line
+    #[cfg(test)]
+    pub fn get_msg_code(&mut self) -> Result<u16> {
+        Ok(self.get_full_response()?.0)
+    }
+
+    /// receive a whole response, which may be multi-line (`code-text` lines until a `code text` line)
+    /// Returns the code and the text of every line, without the `code-`/`code ` prefix
+    pub fn get_full_response(&mut self) -> Result<(u16, Vec<String>)> {
+        let first = self.get_msg_trimed()?;
+        let code = parse_code(&first).ok_or_else(|| anyhow!("no response code in {first:}"))?;
+        let mut lines = vec![strip_code(&first, code).to_string()];
+        if first.as_bytes().get(3) != Some(&b'-') {
+            return Ok((code, lines));
+        }
+        loop {
+            // lines between the first and the last one don't need to start with the code
+            let line = self.get_msg_trimed()?;
+            lines.push(strip_code(&line, code).to_string());
+            if line.starts_with(&format!("{code:} ")) || line == code.to_string() {
+                return Ok((code, lines));
+            }
+        }
+    }
+
+    /// receive a whole response and check its code, `what` names the response in the error, e.g. the command
+    /// Returns the last line of the response, e.g. `227 (127,0,0,1,4,1)`
+    pub fn expect_response(&mut self, what: &str, code: u16) -> Result<String> {
+        let (actual, lines) = self.get_full_response()?;
+        let last = lines.last().map_or("", String::as_str);
+        if actual != code {
+            return Err(anyhow!("{what:}: expected {code:}, got {actual:} {last:}"));
+        }
+        Ok(format!("{actual:} {last:}"))
+    }
+
+    /// send cmd and check the code of its response, see `expect_response`
+    pub fn expect(&mut self, cmd: &str, code: u16) -> Result<String> {
+        self.send_msg_add_crlf(cmd)?;
+        self.expect_response(cmd, code)
+    }
+
+    /// run a scripted dialogue, sending each command and checking the code of its response
+    /// Returns Err describing the first command that got an unexpected response
+    pub fn converse(&mut self, dialogue: &[(&str, u16)]) -> Result<()> {
+        for (cmd, code) in dialogue {
+            self.expect(cmd, *code)?;
+        }
+        Ok(())
+    }
+
+    /// send one line message to server(with appended \r\n)
+    pub fn send_msg_add_crlf(&mut self, msg: &str) -> Result<()> {
+        self.cmd_writer
+            .write_all(format!("{msg:}\r\n").as_bytes())?;
+        self.cmd_writer.flush()?;
+        Ok(())
+    }
+}
+
+/// the leading response code of a response line
+pub fn parse_code(msg: &str) -> Option<u16> {
+    msg.get(..3)?.parse().ok()
+}
+
+/// the text of a response line, after `code-` or `code ` if line starts with it
+fn strip_code(line: &str, code: u16) -> &str {
+    let code = code.to_string();
+    match line.strip_prefix(&code) {
+        Some(rest) if rest.is_empty() => rest,
+        Some(rest) if rest.starts_with(['-', ' ']) => &rest[1..],
+        _ => line,
+    }
+}
+
+/// from `227 (h1,h2,h3,h4,p1,p2)` to `h1.h2.h3.h4:port`
+/// Returns None if s is not a valid PASV response
+pub fn parse_pasv_response(s: &str) -> Option<String> {
+    let mut split = s.split_ascii_whitespace();
+    split.next();
+    let pasv_part = split.next()?;
+    // (..), strip by char rather than byte index so a malformed reply can't split a codepoint
+    let pasv = pasv_part.trim_start_matches('(').trim_end_matches(')');
+    let splited_pasv = pasv.split(',').collect::<Vec<_>>();
+    if splited_pasv.len() != 6 {
+        return None;
+    }
+    let h1 = splited_pasv[0];
+    let h2 = splited_pasv[1];
+    let h3 = splited_pasv[2];
+    let h4 = splited_pasv[3];
+    let p1 = splited_pasv[4];
+    let p2 = splited_pasv[5];
+    let port = decode_pasv_port(p1.parse().ok()?, p2.parse().ok()?);
+    Some(format!("{h1:}.{h2:}.{h3:}.{h4:}:{port:}"))
+}
+
+#[cfg(test)]
+mod test_client_test {
+    use super::*;
+    use std::{net::TcpListener, thread};
+
+    #[test]
+    fn test_converse() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TestClient::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+        let server = thread::spawn(move || {
+            server
+                .write_all(
+                    b"331 Password.\r\n211-Features:\r\n SITE IDLE\r\n211 End\r\n500 Unknown.\r\n",
+                )
+                .unwrap();
+            // hold the connection until the client is done
+            BufReader::new(server).lines().count()
+        });
+
+        client.converse(&[("USER a", 331), ("FEAT", 211)]).unwrap();
+        let err = client.converse(&[("NONE", 200)]).unwrap_err();
+        assert_eq!(err.to_string(), "NONE: expected 200, got 500 Unknown.");
+        drop(client);
+        assert_eq!(server.join().unwrap(), 3);
+    }
+
+    #[test]
+    fn test_strip_code() {
+        assert_eq!(strip_code("211-Features:", 211), "Features:");
+        assert_eq!(strip_code("211 End", 211), "End");
+        assert_eq!(strip_code(" SITE UMASK", 211), " SITE UMASK");
+        assert_eq!(strip_code("211", 211), "");
+        assert_eq!(strip_code("2110 x", 211), "2110 x");
+    }
+}
@@ -0,0 +1,94 @@
+//! # transfer_limit
+//! This module contains `TransferLimit`, the number of running data transfers of each user, shared by all sessions
+//! so that one user can't run unlimited parallel transfers over several control connections
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+pub struct TransferLimit {
+    /// running transfers of each user, users without running transfers are removed
+    running: Mutex<HashMap<String, usize>>,
+    /// 0 means unlimited
+    max_per_user: usize,
+}
+
+impl TransferLimit {
+    pub fn new(max_per_user: usize) -> Self {
+        Self {
+            running: Mutex::new(HashMap::new()),
+            max_per_user,
+        }
+    }
+
+    /// Returns a slot that counts as a running transfer of user until it's dropped
+    /// Returns None if user already runs `max_per_user` transfers
+    pub fn acquire(self: &Arc<Self>, user: &str) -> Option<TransferSlot> {
+        let mut running = self.running.lock().unwrap();
+        let count = running.entry(user.to_string()).or_insert(0);
+        if self.max_per_user != 0 && *count >= self.max_per_user {
+            return None;
+        }
+        *count += 1;
+        Some(TransferSlot {
+            limit: self.clone(),
+            user: user.to_string(),
+        })
+    }
+}
+
+/// A running transfer, released on drop
+pub struct TransferSlot {
+    limit: Arc<TransferLimit>,
+    user: String,
+}
+
+impl Drop for TransferSlot {
+    fn drop(&mut self) {
+        let mut running = self.limit.running.lock().unwrap();
+        if let Some(count) = running.get_mut(&self.user) {
+            *count -= 1;
+            if *count == 0 {
+                running.remove(&self.user);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod transfer_limit_test {
+    use super::*;
+
+    #[test]
+    fn test_acquire() {
+        let limit = Arc::new(TransferLimit::new(2));
+
+        let first = limit.acquire("alice").unwrap();
+        let _second = limit.acquire("alice").unwrap();
+        assert!(limit.acquire("alice").is_none());
+        // other users are counted separately
+        assert!(limit.acquire("bob").is_some());
+
+        drop(first);
+        assert!(limit.acquire("alice").is_some());
+    }
+
+    #[test]
+    fn test_released_users_are_removed() {
+        let limit = Arc::new(TransferLimit::new(1));
+
+        drop(limit.acquire("alice").unwrap());
+        assert!(limit.running.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_unlimited() {
+        let limit = Arc::new(TransferLimit::new(0));
+
+        let slots = (0..100)
+            .map(|_| limit.acquire("alice").unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(slots.len(), 100);
+    }
+}
@@ -0,0 +1,88 @@
+//! # worker_pool
+//! This module contains `WorkerPool`, a fixed number of threads that handle items from a bounded queue,
+//! used to serve connections without spawning a thread for each of them
+
+use std::{
+    sync::{
+        mpsc::{self, Receiver, SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+    thread,
+};
+
+pub struct WorkerPool<T> {
+    sender: SyncSender<T>,
+}
+
+impl<T: Send + 'static> WorkerPool<T> {
+    /// start `workers` threads that call handler on every submitted item,
+    ///     at most `queue_len` items wait for a free worker
+    pub fn new(workers: usize, queue_len: usize, handler: fn(T)) -> Self {
+        let (sender, receiver) = mpsc::sync_channel(queue_len);
+        let receiver = Arc::new(Mutex::new(receiver));
+        for _ in 0..workers.max(1) {
+            let receiver = receiver.clone();
+            thread::spawn(move || Self::work(&receiver, handler));
+        }
+        Self { sender }
+    }
+
+    fn work(receiver: &Mutex<Receiver<T>>, handler: fn(T)) {
+        loop {
+            // the lock is released before handling, so other workers can take the next item
+            let item = receiver.lock().unwrap().recv();
+            match item {
+                Ok(item) => handler(item),
+                // the pool is dropped
+                Err(_) => return,
+            }
+        }
+    }
+
+    /// queue item for a worker
+    /// Returns Err(item) without blocking if the queue is full
+    pub fn try_submit(&self, item: T) -> Result<(), T> {
+        self.sender.try_send(item).map_err(|e| match e {
+            TrySendError::Full(item) | TrySendError::Disconnected(item) => item,
+        })
+    }
+}
+
+#[cfg(test)]
+mod worker_pool_test {
+    use super::*;
+    use std::sync::mpsc::Sender;
+
+    #[test]
+    fn test_try_submit_backpressure() {
+        // each item blocks its worker until the item's receiver gets something, then reports done
+        type Item = (Receiver<()>, Sender<usize>, usize);
+        let pool = WorkerPool::new(1, 1, |(release, done, id): Item| {
+            release.recv().unwrap();
+            done.send(id).unwrap();
+        });
+        let (done_tx, done_rx) = mpsc::channel();
+        let mut releases = Vec::new();
+        let mut item = |id| {
+            let (release, wait) = mpsc::channel();
+            releases.push(release);
+            (wait, done_tx.clone(), id)
+        };
+
+        pool.try_submit(item(0)).unwrap();
+        // wait until the worker took item 0, so item 1 stays in the queue
+        while pool.try_submit(item(1)).is_err() {}
+        let rejected = loop {
+            if let Err(rejected) = pool.try_submit(item(2)) {
+                break rejected;
+            }
+        };
+        assert_eq!(rejected.2, 2);
+
+        for release in &releases {
+            let _ = release.send(());
+        }
+        assert_eq!(done_rx.recv().unwrap(), 0);
+        assert_eq!(done_rx.recv().unwrap(), 1);
+    }
+}